@@ -0,0 +1,127 @@
+//! Measures how long well-behaved clients wait for a response while slow-loris style clients hold
+//! connections open by dribbling request headers one byte at a time.
+//!
+//! Usage: `cargo run --release --bin accept_latency -- <host:port> [slow clients] [samples]`
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use site_3ds_bench::Summary;
+
+const DRIBBLE_INTERVAL: Duration = Duration::from_millis(500);
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn slow_client(address: SocketAddr, running: Arc<AtomicBool>, open: Arc<AtomicUsize>) {
+    let header = b"GET / HTTP/1.1\r\nHost: slow\r\nX-Slow: ";
+    while running.load(Ordering::Relaxed) {
+        let mut stream = match TcpStream::connect_timeout(&address, TIMEOUT) {
+            Ok(stream) => stream,
+            Err(_) => {
+                thread::sleep(DRIBBLE_INTERVAL);
+                continue;
+            }
+        };
+        open.fetch_add(1, Ordering::Relaxed);
+        // Never finish the headers, just keep the server waiting for more.
+        for byte in header.iter().chain(std::iter::repeat(&b'a')) {
+            if !running.load(Ordering::Relaxed) || stream.write_all(&[*byte]).is_err() {
+                break;
+            }
+            thread::sleep(DRIBBLE_INTERVAL);
+        }
+        open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the time to connect and the time until the first byte of the response arrives.
+fn sample(address: SocketAddr) -> Option<(Duration, Duration)> {
+    let started = Instant::now();
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT).ok()?;
+    let connected = started.elapsed();
+    stream.set_read_timeout(Some(TIMEOUT)).ok()?;
+    stream
+        .write_all(b"GET /healthz HTTP/1.1\r\nHost: bench\r\n\r\n")
+        .ok()?;
+
+    let mut buffer = [0; 512];
+    let read = stream.read(&mut buffer).ok()?;
+    if read == 0 {
+        return None;
+    }
+    let first_byte = started.elapsed();
+    // Drain the rest so the server isn't stuck writing to us.
+    while let Ok(read) = stream.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+    }
+    Some((connected, first_byte))
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let address = match args.next().and_then(|address| address.to_socket_addrs().ok()?.next()) {
+        Some(address) => address,
+        None => {
+            eprintln!("usage: accept_latency <host:port> [slow clients] [samples]");
+            std::process::exit(1);
+        }
+    };
+    let slow_clients = args.next().and_then(|value| value.parse().ok()).unwrap_or(20);
+    let samples = args.next().and_then(|value| value.parse().ok()).unwrap_or(200);
+
+    let running = Arc::new(AtomicBool::new(true));
+    let open = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..slow_clients)
+        .map(|_| {
+            let running = running.clone();
+            let open = open.clone();
+            thread::spawn(move || slow_client(address, running, open))
+        })
+        .collect();
+
+    // Give the slow clients time to get their connections in.
+    thread::sleep(Duration::from_secs(2));
+    println!(
+        "{} slow clients connected, taking {} samples from {}",
+        open.load(Ordering::Relaxed),
+        samples,
+        address
+    );
+
+    let mut connects = vec![];
+    let mut first_bytes = vec![];
+    let mut failures = 0;
+    for _ in 0..samples {
+        match sample(address) {
+            Some((connected, first_byte)) => {
+                connects.push(connected);
+                first_bytes.push(first_byte);
+            }
+            None => failures += 1,
+        }
+        thread::sleep(SAMPLE_INTERVAL);
+    }
+
+    running.store(false, Ordering::Relaxed);
+    if let Some(summary) = Summary::new(&mut connects) {
+        summary.print("connect");
+    }
+    if let Some(summary) = Summary::new(&mut first_bytes) {
+        summary.print("first byte");
+    }
+    println!("failed      {}", failures);
+
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
@@ -0,0 +1,289 @@
+//! Replays page loads the way a browser makes them, from a number of clients at once, and reports how long
+//! each kind of request took. A page load is the index, everything it links to, the book covers, the API
+//! calls the page makes on load and a couple of chunks of the video. Ratings are only read, never posted.
+//!
+//! Usage: `cargo run --release --bin load -- <host:port> [clients] [seconds]`
+//!
+//! The server rate limits each client address, so turn `rate_limit` off in its config first or most of the
+//! requests will get 429s.
+
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use site_3ds_bench::Summary;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const ACCEPT_ENCODING: &str = "gzip, deflate, br, zstd";
+const BOOKS: &[&str] = &[
+    "/books/HNI_0002.jpg",
+    "/books/HNI_0003.jpg",
+    "/books/HNI_0004.jpg",
+    "/books/HNI_0006.jpg",
+    "/books/HNI_0005.jpg",
+    "/books/HNI_0007.jpg",
+    "/books/HNI_0010.jpg",
+];
+const API_CALLS: &[&str] = &["/api/review_ratings", "/api/visits"];
+const VIDEO: &str = "/video.webm";
+/// Browsers fetch media a piece at a time, this is roughly the size of each piece.
+const VIDEO_CHUNK: usize = 256 * 1024;
+
+struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Sends a GET on a connection of its own and reads the whole response.
+fn get(address: SocketAddr, path: &str, headers: &[String]) -> Option<Reply> {
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT).ok()?;
+    stream.set_read_timeout(Some(TIMEOUT)).ok()?;
+    let mut request = format!("GET {} HTTP/1.1\r\nHost: load\r\n", path);
+    for header in headers {
+        request.push_str(header);
+        request.push_str("\r\n");
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).ok()?;
+
+    let mut data = vec![];
+    let mut buffer = [0; 8192];
+    let head_end = loop {
+        if let Some(position) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position;
+        }
+        let read = stream.read(&mut buffer).ok()?;
+        if read == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buffer[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+        .collect();
+    let mut reply = Reply {
+        status,
+        headers,
+        body: data[head_end + 4..].to_vec(),
+    };
+
+    let length = reply.header("Content-Length").and_then(|length| length.parse().ok()).unwrap_or(0);
+    while reply.body.len() < length {
+        let read = stream.read(&mut buffer).ok()?;
+        if read == 0 {
+            return None;
+        }
+        reply.body.extend_from_slice(&buffer[..read]);
+    }
+    Some(reply)
+}
+
+/// The local scripts, styles and images the index links to, as the browser would fetch them next.
+fn linked_assets(index: &str) -> Vec<String> {
+    let mut assets = vec![];
+    for attribute in ["src=\"/", "href=\"/"] {
+        for (start, _) in index.match_indices(attribute) {
+            let path_start = start + attribute.len() - 1;
+            if let Some(length) = index[path_start..].find('"') {
+                let path = &index[path_start..path_start + length];
+                if path.len() > 1 && !path.starts_with("//") && !assets.iter().any(|asset| asset == path) {
+                    assets.push(path.to_string());
+                }
+            }
+        }
+    }
+    assets
+}
+
+/// Latencies by kind of request, and how many of each status came back.
+#[derive(Default)]
+struct Results {
+    latencies: BTreeMap<&'static str, Vec<Duration>>,
+    page_loads: Vec<Duration>,
+    statuses: BTreeMap<u16, usize>,
+    failures: usize,
+    bytes: usize,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        for (kind, mut latencies) in other.latencies {
+            self.latencies.entry(kind).or_default().append(&mut latencies);
+        }
+        self.page_loads.extend(other.page_loads);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        self.failures += other.failures;
+        self.bytes += other.bytes;
+    }
+
+    fn requests(&self) -> usize {
+        self.statuses.values().sum::<usize>() + self.failures
+    }
+}
+
+struct Client {
+    address: SocketAddr,
+    assets: Arc<Vec<String>>,
+    results: Results,
+    /// Where the seek into the video lands, a little further each page load.
+    video_offset: usize,
+}
+
+impl Client {
+    fn fetch(&mut self, kind: &'static str, path: &str, range: Option<&str>) -> Option<Reply> {
+        let mut headers = vec![format!("Accept-Encoding: {}", ACCEPT_ENCODING)];
+        if let Some(range) = range {
+            headers.push(format!("Range: {}", range));
+        }
+        let started = Instant::now();
+        let reply = get(self.address, path, &headers);
+        match &reply {
+            Some(reply) => {
+                self.results.latencies.entry(kind).or_default().push(started.elapsed());
+                *self.results.statuses.entry(reply.status).or_default() += 1;
+                self.results.bytes += reply.body.len();
+            }
+            None => self.results.failures += 1,
+        }
+        reply
+    }
+
+    fn page_load(&mut self) {
+        let started = Instant::now();
+        self.fetch("index", "/", None);
+        for asset in self.assets.clone().iter() {
+            self.fetch("assets", asset, None);
+        }
+        for book in BOOKS {
+            self.fetch("books", book, None);
+        }
+        for call in API_CALLS {
+            self.fetch("api", call, None);
+        }
+
+        // The start of the video, then a seek somewhere further in.
+        let length = self
+            .fetch("video", VIDEO, Some(&format!("bytes=0-{}", VIDEO_CHUNK - 1)))
+            .and_then(|reply| reply.header("Content-Range")?.rsplit_once('/')?.1.parse::<usize>().ok());
+        if let Some(length) = length
+            && length > VIDEO_CHUNK
+        {
+            self.video_offset = (self.video_offset + VIDEO_CHUNK) % length;
+            let range = format!("bytes={}-{}", self.video_offset, self.video_offset + VIDEO_CHUNK - 1);
+            self.fetch("video", VIDEO, Some(&range));
+        }
+        self.results.page_loads.push(started.elapsed());
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let address = match args.next().and_then(|address| address.to_socket_addrs().ok()?.next()) {
+        Some(address) => address,
+        None => {
+            eprintln!("usage: load <host:port> [clients] [seconds]");
+            std::process::exit(1);
+        }
+    };
+    let clients = args.next().and_then(|value| value.parse().ok()).unwrap_or(4);
+    let duration = Duration::from_secs(args.next().and_then(|value| value.parse().ok()).unwrap_or(30));
+
+    // Uncompressed, to find the links in.
+    let assets = match get(address, "/", &[]) {
+        Some(index) if index.status == 200 => linked_assets(&String::from_utf8_lossy(&index.body)),
+        Some(index) => {
+            eprintln!("{} answered / with {}", address, index.status);
+            std::process::exit(1);
+        }
+        None => {
+            eprintln!("{} didn't answer", address);
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "{} clients loading pages from {} for {:?}, {} linked assets",
+        clients,
+        address,
+        duration,
+        assets.len()
+    );
+
+    let running = Arc::new(AtomicBool::new(true));
+    let results = Arc::new(Mutex::new(Results::default()));
+    let assets = Arc::new(assets);
+    let started = Instant::now();
+    let threads: Vec<_> = (0..clients)
+        .map(|client| {
+            let running = running.clone();
+            let results = results.clone();
+            let mut client = Client {
+                address,
+                assets: assets.clone(),
+                results: Results::default(),
+                video_offset: client * VIDEO_CHUNK,
+            };
+            thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    client.page_load();
+                }
+                results.lock().unwrap().merge(client.results);
+            })
+        })
+        .collect();
+
+    thread::sleep(duration);
+    running.store(false, Ordering::Relaxed);
+    for thread in threads {
+        let _ = thread.join();
+    }
+    let elapsed = started.elapsed();
+
+    let mut results = Arc::try_unwrap(results).ok().unwrap().into_inner().unwrap();
+    for (kind, latencies) in results.latencies.iter_mut() {
+        if let Some(summary) = Summary::new(latencies) {
+            summary.print(kind);
+        }
+    }
+    if let Some(summary) = Summary::new(&mut results.page_loads) {
+        summary.print("page load");
+    }
+    println!(
+        "{} requests in {:.1?}, {:.1} requests/s, {:.1} KiB/s",
+        results.requests(),
+        elapsed,
+        results.requests() as f64 / elapsed.as_secs_f64(),
+        results.bytes as f64 / 1024.0 / elapsed.as_secs_f64()
+    );
+    for (status, count) in &results.statuses {
+        println!("{:<12} {}", status, count);
+    }
+    println!("failed       {}", results.failures);
+    if results.statuses.contains_key(&429) {
+        println!("Some requests were rate limited, turn rate_limit off in the server's config to measure it");
+    }
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+/// Latency percentiles over a set of samples.
+pub struct Summary {
+    pub count: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Summary {
+    pub fn new(samples: &mut [Duration]) -> Option<Summary> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort();
+        let percentile = |p: usize| samples[((samples.len() - 1) * p) / 100];
+        Some(Summary {
+            count: samples.len(),
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+            max: samples[samples.len() - 1],
+        })
+    }
+
+    pub fn print(&self, name: &str) {
+        println!(
+            "{:<12} n={:<6} p50={:>8.2?} p95={:>8.2?} p99={:>8.2?} max={:>8.2?}",
+            name, self.count, self.p50, self.p95, self.p99, self.max
+        );
+    }
+}
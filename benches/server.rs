@@ -0,0 +1,80 @@
+//! `cargo bench` on the host. The 3DS is far slower, so these are for comparing changes, not for capacity.
+
+use std::hint::black_box;
+use std::sync::{Arc, Mutex};
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use site_3ds::config::DatabaseConfig;
+use site_3ds::database::Database;
+use site_3ds::handler::{Router, SERVE_REQUESTS};
+use site_3ds::http_utils::{Request, parse_range, select_encoding};
+use site_3ds::metrics::Metrics;
+
+const BROWSER_REQUEST: &[u8] = b"GET /books/HNI_0002.jpg HTTP/1.1\r\n\
+Host: 192.168.1.20:8080\r\n\
+User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0\r\n\
+Accept: image/avif,image/webp,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5\r\n\
+Accept-Language: en-US,en;q=0.5\r\n\
+Accept-Encoding: gzip, deflate, br, zstd\r\n\
+Connection: keep-alive\r\n\
+Referer: http://192.168.1.20:8080/\r\n\
+Range: bytes=1024-\r\n\
+\r\n";
+
+const API_REQUEST: &[u8] = b"POST /api/review_ratings HTTP/1.1\r\n\
+Host: 192.168.1.20:8080\r\n\
+Content-Type: application/json\r\n\
+Content-Length: 27\r\n\
+\r\n\
+{\"id\": 2, \"positive\": true}";
+
+fn parsing(c: &mut Criterion) {
+    c.bench_function("is_complete", |b| b.iter(|| Request::is_complete(black_box(BROWSER_REQUEST))));
+    c.bench_function("parse browser request", |b| b.iter(|| Request::parse(black_box(BROWSER_REQUEST))));
+    c.bench_function("parse api request", |b| b.iter(|| Request::parse(black_box(API_REQUEST))));
+
+    let request = Request::parse(BROWSER_REQUEST).unwrap();
+    c.bench_function("get_header", |b| b.iter(|| request.get_header(black_box("range"))));
+    c.bench_function("parse_range", |b| b.iter(|| parse_range(black_box("bytes=1024-"), black_box(1 << 20))));
+}
+
+fn routing(c: &mut Criterion) {
+    let config = DatabaseConfig {
+        filename: std::env::temp_dir().join("site_3ds_bench_database.bin").to_string_lossy().into_owned(),
+        ..DatabaseConfig::default()
+    };
+    let router = Router::new(Arc::new(Mutex::new(Database::new(&config))), Arc::new(Metrics::new(100)));
+
+    let last_asset = SERVE_REQUESTS[SERVE_REQUESTS.len() - 1].path;
+    let routes = [
+        ("route index", "/"),
+        ("route last asset", last_asset),
+        ("route api", "/api/review_ratings"),
+        ("route not found", "/no/such/page"),
+    ];
+    for (name, path) in routes {
+        let request = Request::parse(format!("GET {} HTTP/1.1\r\nAccept-Encoding: gzip, br\r\n\r\n", path).as_bytes()).unwrap();
+        c.bench_function(name, |b| b.iter(|| router.route(black_box(&request))));
+    }
+}
+
+fn encoding_selection(c: &mut Criterion) {
+    let identity = vec![0; 4096];
+    let (gzip, deflate, br, zstd) = (vec![0; 1500], vec![0; 1490], vec![0; 1300], vec![0; 1400]);
+    let encoded = [
+        ("gzip", Some(&gzip[..])),
+        ("deflate", Some(&deflate[..])),
+        ("br", Some(&br[..])),
+        ("zstd", Some(&zstd[..])),
+    ];
+    for (name, accept) in [
+        ("select_encoding browser", "gzip, deflate, br, zstd"),
+        ("select_encoding weighted", "br;q=0.9, gzip;q=1.0, *;q=0.1, zstd;q=0"),
+        ("select_encoding identity", ""),
+    ] {
+        c.bench_function(name, |b| b.iter(|| select_encoding(black_box(accept), &identity, &encoded)));
+    }
+}
+
+criterion_group!(benches, parsing, routing, encoding_selection);
+criterion_main!(benches);
@@ -130,6 +130,14 @@ fn zstd_encode_data(
     }
 }
 
+fn error_page_status(path: &Path) -> Option<u16> {
+    let name = path.to_str()?.strip_suffix(".html")?;
+    if name.len() != 3 || !name.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    name.parse().ok().filter(|status| (400..600).contains(status))
+}
+
 fn main() {
     let encoders: [Encoder; 4] = [
         Encoder {
@@ -178,6 +186,7 @@ fn main() {
     );
 
     let mut entries = vec![];
+    let mut error_pages = vec![];
 
     let mut data_section = String::new();
     let empty_body_name = "EMPTY_BODY";
@@ -204,6 +213,12 @@ fn main() {
         let data_name = format!("DATA_{}", raw_name);
         write_vec_to_contents(&mut data_section, data_name.as_str(), &raw);
 
+        // `404.html` and friends at the top of dist are the bodies for those statuses, not pages of their own.
+        if let Some(status) = error_page_status(trimmed_path) {
+            error_pages.push((status, data_name));
+            continue;
+        }
+
         let mime_type = mime_guess::from_path(path).first_or_octet_stream();
 
         entries_section.push_str(&format!(
@@ -244,6 +259,15 @@ fn main() {
     }
     contents.push_str("];\n");
 
+    contents.push_str(&format!(
+        "pub const ERROR_PAGES: [(u16, &[u8]); {}] = [\n",
+        error_pages.len()
+    ));
+    for (status, data_name) in error_pages {
+        contents.push_str(&format!("    ({}, &{}),\n", status, data_name));
+    }
+    contents.push_str("];\n");
+
     fs::write(&dest_path, contents).unwrap();
     println!("cargo::rerun-if-changed=site/public");
     println!("cargo::rerun-if-changed=site/src");
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use site_3ds::http_utils::{accepts_encoding, select_encoding};
+
+fuzz_target!(|input: (&str, &[u8], Option<&[u8]>, Option<&[u8]>)| {
+    let (accept, identity, gzip, br) = input;
+    let (body, coding) = select_encoding(accept, identity, &[("gzip", gzip), ("br", br)]);
+    assert!(body.len() <= identity.len());
+    assert!(coding.is_empty() || accepts_encoding(accept, coding));
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use site_3ds::api;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = api::parse_review_rating(data);
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use site_3ds::http_utils::Request;
+
+// The first line of the input is the header name to look up, the rest is the request.
+fuzz_target!(|data: &[u8]| {
+    let Some(split) = data.iter().position(|byte| *byte == b'\n') else {
+        return;
+    };
+    let (Ok(name), Some(request)) = (std::str::from_utf8(&data[..split]), Request::parse(&data[split + 1..])) else {
+        return;
+    };
+    for value in request.get_headers(name) {
+        assert_eq!(value, value.trim());
+    }
+    let _ = request.get_header(name);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use site_3ds::http_utils::Request;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Request::is_complete(data);
+    let _ = Request::parse(data);
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use std::net::SocketAddr;
+
+use libfuzzer_sys::fuzz_target;
+use site_3ds::proxy::{IpCidr, ProxyProtocolMode, accept_proxy_header, parse_proxy_header};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(Some(header)) = parse_proxy_header(data) {
+        assert!(header.len <= data.len());
+    }
+
+    let trusted: [IpCidr; 1] = ["10.0.0.0/8".parse().unwrap()];
+    let peer = SocketAddr::from(([10, 0, 0, 1], 40000));
+    for mode in [ProxyProtocolMode::Off, ProxyProtocolMode::Optional, ProxyProtocolMode::Strict] {
+        if let Ok((_, len)) = accept_proxy_header(mode, &peer, data, &trusted) {
+            assert!(len <= data.len());
+        }
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use site_3ds::http_utils::{RangeRequest, parse_range};
+
+fuzz_target!(|input: (&str, usize)| {
+    let (header, len) = input;
+    if let RangeRequest::Satisfiable(range) = parse_range(header, len) {
+        assert!(range.start < range.end && range.end <= len);
+    }
+});
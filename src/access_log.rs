@@ -0,0 +1,366 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::AccessLogConfig;
+use crate::http_utils::{Request, Response};
+use crate::logger::{error, info};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// `host - - [time] "request" status bytes`
+    Common,
+    /// Common plus the referer and user agent, with the encoding and duration tacked on the end.
+    #[default]
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Everything the access log records about a single response.
+pub struct AccessEntry {
+    pub client_ip: IpAddr,
+    pub time: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+    pub version: f32,
+    pub status: u16,
+    /// Everything written to the socket, headers included.
+    pub bytes: usize,
+    pub encoding: String,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub duration: Duration,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    time: String,
+    client_ip: String,
+    method: &'a str,
+    path: &'a str,
+    version: String,
+    status: u16,
+    bytes: usize,
+    encoding: &'a str,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    duration_ms: f64,
+}
+
+impl AccessEntry {
+    /// `started` is when the request first reached us, the entry is timed from then to now.
+    pub fn new(request: &Request, response: &Response, bytes: usize, started: Instant) -> Self {
+        let duration = started.elapsed();
+        Self {
+            client_ip: request.client_ip(),
+            time: Utc::now() - duration,
+            method: request.method.clone(),
+            path: request.path.clone(),
+            version: request.version,
+            status: response.status,
+            bytes,
+            encoding: response.encoding().to_string(),
+            referer: request.get_header("Referer"),
+            user_agent: request.get_header("User-Agent"),
+            duration,
+        }
+    }
+
+    fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => self.common(),
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\" {} {}",
+                self.common(),
+                escape(self.referer.as_deref().unwrap_or("-")),
+                escape(self.user_agent.as_deref().unwrap_or("-")),
+                self.encoding,
+                self.duration.as_millis()
+            ),
+            AccessLogFormat::Json => serde_json::to_string(&JsonEntry {
+                time: self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+                client_ip: self.client_ip.to_string(),
+                method: &self.method,
+                path: &self.path,
+                version: format!("HTTP/{}", self.version),
+                status: self.status,
+                bytes: self.bytes,
+                encoding: &self.encoding,
+                referer: self.referer.as_deref(),
+                user_agent: self.user_agent.as_deref(),
+                duration_ms: self.duration.as_secs_f64() * 1000.0,
+            })
+            .unwrap_or_default(),
+        }
+    }
+
+    fn common(&self) -> String {
+        format!(
+            "{} - - [{}] \"{} {} HTTP/{}\" {} {}",
+            self.client_ip,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&self.method),
+            escape(&self.path),
+            self.version,
+            self.status,
+            // CLF writes an empty body as a dash.
+            if self.bytes == 0 { "-".to_string() } else { self.bytes.to_string() }
+        )
+    }
+
+    /// Short enough to fit on a line of the top screen.
+    fn console(&self) -> String {
+        format!(
+            "{} {} {} {} {}ms",
+            self.time.format("%H:%M:%S"),
+            self.status,
+            self.method,
+            self.path,
+            self.duration.as_millis()
+        )
+    }
+}
+
+/// Keeps quotes and control characters sent by the client from breaking up the log line.
+fn escape(value: &str) -> String {
+    value.escape_default().to_string()
+}
+
+struct LogFile {
+    writer: BufWriter<File>,
+    size: u64,
+    last_flush: Instant,
+}
+
+/// Writes access log lines to the SD card through a buffer, rolling the file over once it gets too big.
+pub struct AccessLog {
+    config: AccessLogConfig,
+    file: Mutex<Option<LogFile>>,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Self {
+        let file = if config.enabled { Self::open(config) } else { None };
+        Self {
+            config: config.clone(),
+            file: Mutex::new(file),
+        }
+    }
+
+    fn open(config: &AccessLogConfig) -> Option<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.filename);
+        match file {
+            Ok(file) => {
+                let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                Some(LogFile {
+                    writer: BufWriter::with_capacity(config.buffer_bytes, file),
+                    size,
+                    last_flush: Instant::now(),
+                })
+            }
+            Err(e) => {
+                error!("Error opening {}: {e}", config.filename);
+                None
+            }
+        }
+    }
+
+    /// `access.log` becomes `access.log.1`, `access.log.1` becomes `access.log.2` and so on, with the oldest
+    /// falling off the end.
+    fn rotate(&self, file: &mut Option<LogFile>) {
+        if let Some(mut current) = file.take() {
+            let _ = current.writer.flush();
+        }
+
+        let filename = &self.config.filename;
+        let _ = fs::remove_file(format!("{}.{}", filename, self.config.max_files));
+        for i in (1..self.config.max_files).rev() {
+            let _ = fs::rename(format!("{}.{}", filename, i), format!("{}.{}", filename, i + 1));
+        }
+        if self.config.max_files > 0 {
+            let _ = fs::rename(filename, format!("{}.1", filename));
+        } else {
+            let _ = fs::remove_file(filename);
+        }
+
+        *file = Self::open(&self.config);
+    }
+
+    pub fn log(&self, entry: &AccessEntry) {
+        info!("{}", entry.console());
+        if !self.config.enabled {
+            return;
+        }
+
+        let mut line = entry.format(self.config.format);
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        let too_big = file
+            .as_ref()
+            .is_some_and(|file| file.size > 0 && file.size + line.len() as u64 > self.config.max_bytes);
+        if too_big {
+            self.rotate(&mut file);
+        }
+        if let Some(current) = file.as_mut() {
+            match current.writer.write_all(line.as_bytes()) {
+                Ok(()) => current.size += line.len() as u64,
+                Err(e) => error!("Error writing access log: {e}"),
+            }
+        }
+    }
+
+    /// Called every frame, pushes out whatever is buffered once the flush interval has passed.
+    pub fn step(&self) {
+        let mut file = self.file.lock().unwrap();
+        if let Some(current) = file.as_mut()
+            && current.last_flush.elapsed() >= Duration::from_millis(self.config.flush_interval_ms)
+        {
+            self.flush_file(current);
+        }
+    }
+
+    pub fn flush(&self) {
+        if let Some(current) = self.file.lock().unwrap().as_mut() {
+            self.flush_file(current);
+        }
+    }
+
+    fn flush_file(&self, current: &mut LogFile) {
+        if let Err(e) = current.writer.flush() {
+            error!("Error flushing access log: {e}");
+        }
+        current.last_flush = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn entry() -> AccessEntry {
+        AccessEntry {
+            client_ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 7)),
+            time: Utc.with_ymd_and_hms(2025, 1, 31, 13, 5, 9).unwrap(),
+            method: "GET".to_string(),
+            path: "/books/HNI_0002.jpg".to_string(),
+            version: 1.1,
+            status: 200,
+            bytes: 5120,
+            encoding: "br".to_string(),
+            referer: Some("http://3ds.local/".to_string()),
+            user_agent: Some("Mozilla/5.0 (Nintendo 3DS)".to_string()),
+            duration: Duration::from_millis(42),
+        }
+    }
+
+    #[test]
+    fn common_format() {
+        assert_eq!(
+            entry().format(AccessLogFormat::Common),
+            r#"192.168.1.7 - - [31/Jan/2025:13:05:09 +0000] "GET /books/HNI_0002.jpg HTTP/1.1" 200 5120"#
+        );
+    }
+
+    #[test]
+    fn combined_format() {
+        assert_eq!(
+            entry().format(AccessLogFormat::Combined),
+            r#"192.168.1.7 - - [31/Jan/2025:13:05:09 +0000] "GET /books/HNI_0002.jpg HTTP/1.1" 200 5120 "http://3ds.local/" "Mozilla/5.0 (Nintendo 3DS)" br 42"#
+        );
+    }
+
+    #[test]
+    fn missing_values_are_dashes() {
+        let entry = AccessEntry {
+            bytes: 0,
+            referer: None,
+            user_agent: None,
+            ..entry()
+        };
+        assert_eq!(
+            entry.format(AccessLogFormat::Combined),
+            r#"192.168.1.7 - - [31/Jan/2025:13:05:09 +0000] "GET /books/HNI_0002.jpg HTTP/1.1" 200 - "-" "-" br 42"#
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let line: serde_json::Value = serde_json::from_str(&entry().format(AccessLogFormat::Json)).unwrap();
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "time": "2025-01-31T13:05:09.000Z",
+                "client_ip": "192.168.1.7",
+                "method": "GET",
+                "path": "/books/HNI_0002.jpg",
+                "version": "HTTP/1.1",
+                "status": 200,
+                "bytes": 5120,
+                "encoding": "br",
+                "referer": "http://3ds.local/",
+                "user_agent": "Mozilla/5.0 (Nintendo 3DS)",
+                "duration_ms": 42.0,
+            })
+        );
+    }
+
+    #[test]
+    fn client_values_cannot_break_the_line() {
+        let entry = AccessEntry {
+            path: "/\"a\"\r\n127.0.0.1 - - [fake]".to_string(),
+            user_agent: Some("x\" \"y\\\u{1b}[31m".to_string()),
+            ..entry()
+        };
+        let line = entry.format(AccessLogFormat::Combined);
+        assert!(!line.contains('\n') && !line.contains('\r') && !line.contains('\u{1b}'));
+        assert!(line.contains(r#""GET /\"a\"\r\n127.0.0.1 - - [fake] HTTP/1.1""#), "{}", line);
+        assert!(line.contains(r#""x\" \"y\\\u{1b}[31m""#), "{}", line);
+
+        // JSON does its own escaping, the values go in as they came.
+        let line: serde_json::Value = serde_json::from_str(&entry.format(AccessLogFormat::Json)).unwrap();
+        assert_eq!(line["path"], entry.path);
+    }
+
+    #[test]
+    fn writes_and_rotates_the_file() {
+        let filename = std::env::temp_dir().join(format!("site_3ds_access_{}.log", std::process::id()));
+        let filename = filename.to_string_lossy().into_owned();
+        let remove = || {
+            for name in [filename.clone(), format!("{}.1", filename), format!("{}.2", filename)] {
+                let _ = fs::remove_file(name);
+            }
+        };
+        remove();
+
+        let line_len = entry().format(AccessLogFormat::Common).len() as u64 + 1;
+        let log = AccessLog::new(&AccessLogConfig {
+            filename: filename.clone(),
+            format: AccessLogFormat::Common,
+            max_bytes: line_len * 2,
+            max_files: 1,
+            ..AccessLogConfig::default()
+        });
+        for _ in 0..5 {
+            log.log(&entry());
+        }
+        log.flush();
+
+        let current = fs::read_to_string(&filename).unwrap();
+        assert_eq!(current.lines().count(), 1);
+        assert_eq!(current.trim_end(), entry().format(AccessLogFormat::Common));
+        assert_eq!(fs::read_to_string(format!("{}.1", filename)).unwrap().lines().count(), 2);
+        assert!(fs::metadata(format!("{}.2", filename)).is_err());
+        remove();
+    }
+}
@@ -1,13 +1,15 @@
-use std::{collections::HashMap, net::IpAddr, str::FromStr, sync::{Arc, Mutex}};
-
-use core::net::SocketAddr;
+use std::{collections::HashMap, sync::{Arc, Mutex}};
 
 use crate::{
     database::Database,
+    errors,
     http_utils::{Request, Response, ResponseBody, content_types},
+    timing,
 };
 use serde::{Deserialize, Serialize};
 
+pub const ROUTES: [&str; 2] = ["/api/review_ratings", "/api/visits"];
+
 #[derive(Serialize)]
 struct ApiResponse<T: Serialize> {
     data: T,
@@ -19,9 +21,48 @@ impl<T: Serialize> ApiResponse<T> {
     }
 }
 
-#[derive(Serialize)]
-struct NotFoundResponse {
-    message: String,
+/// Everything an API call can fail with, sent as the JSON error envelope.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request body isn't the JSON the route expects.
+    InvalidBody(serde_json::Error),
+    NotFound,
+    /// The route exists but not for this method.
+    MethodNotAllowed,
+}
+
+impl ApiError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::InvalidBody(_) => 400,
+            ApiError::NotFound => 404,
+            ApiError::MethodNotAllowed => 405,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidBody(_) => "invalid_body",
+            ApiError::NotFound => "not_found",
+            ApiError::MethodNotAllowed => "method_not_allowed",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::InvalidBody(e) => format!("Invalid request body: {e}"),
+            ApiError::NotFound => "No such API route".to_string(),
+            ApiError::MethodNotAllowed => "Method not allowed for this API route".to_string(),
+        }
+    }
+
+    pub fn response<'a>(&self, request: &Request) -> Response<'a> {
+        let mut response = errors::json(self.status(), self.code(), &self.message());
+        if let ApiError::MethodNotAllowed = self {
+            response.headers.push(format!("Allow: {}", allowed_methods(&request.path).join(", ")));
+        }
+        response
+    }
 }
 
 #[derive(Serialize)]
@@ -51,72 +92,87 @@ pub struct PostReviewRatingRequest {
     pub positive: bool,
 }
 
-pub fn route<'a>(
-    request: &Request,
-    db: Arc<Mutex<Database>>,
-    socket_addr: &SocketAddr,
-) -> Option<Response<'a>> {
-    if request.method == "GET" && request.path == "/api/review_ratings" {
-        let db = db.lock().unwrap();
-        let mut response = Response::new();
-        response.content_type = content_types::JSON;
-        response.body = ApiResponse::new(ReviewRatingsResponse {
-            review_ratings: db.get_review_ratings(),
-        });
-        return Some(response);
+/// The methods each route answers, besides `OPTIONS`.
+pub fn allowed_methods(path: &str) -> &'static [&'static str] {
+    match path {
+        "/api/review_ratings" => &["GET", "POST"],
+        "/api/visits" => &["GET"],
+        _ => &[],
     }
+}
 
-    if request.method == "POST" && request.path == "/api/review_ratings" {
-        let mut db = db.lock().unwrap();
-        let response = match serde_json::from_str::<PostReviewRatingRequest>(&request.body) {
-            Ok(request_body) => {
-                let mut response = Response::new();
-                response.content_type = content_types::JSON;
-                db.add_review_rating(request_body.id, if request_body.positive { 1 } else { -1 });
-                response.body = ApiResponse::new(ReviewRatingResponse {
-                    id: request_body.id,
-                    rating: db.get_review_rating(request_body.id),
-                });
-                response
-            }
-            Err(e) => {
-                let mut response = Response::new();
-                response.status = 400;
-                response.content_type = content_types::JSON;
-                response.body = ApiResponse::new(NotFoundResponse {
-                    message: format!("Invalid request body: {e}"),
-                });
-                response
-            }
-        };
-        return Some(response);
-    }
+fn json_response<'a, T: Serialize>(data: T) -> Response<'a> {
+    let mut response = Response::new();
+    response.content_type = content_types::JSON;
+    response.body = ApiResponse::new(data);
+    response
+}
 
-    if request.method == "GET" && request.path == "/api/visits" {
-        let mut db = db.lock().unwrap();
-        let ip = match request.get_header("X-Forwarded-For") {
-            Some(value) => {
-                println!("X-Forwarded-For: {value}");
-                match IpAddr::from_str(&value) {
-                    Ok(ip_address) => ip_address,
-                    Err(e) => {
-                        println!("Error parsing IP address: {e}");
-                        socket_addr.ip()
-                    }
-                }
-            }
-            None => socket_addr.ip(),
-        };
-
-        db.add_visit(&ip);
-
-        let mut response = Response::new();
-        response.content_type = content_types::JSON;
-        response.body = ApiResponse::new(VisitsResponse {
-            visits: db.get_visits(),
-        });
-        return Some(response);
-    }
+fn get_review_ratings<'a>(db: &Mutex<Database>) -> Result<Response<'a>, ApiError> {
+    let db = timing::timed_lock(db);
+    Ok(json_response(ReviewRatingsResponse {
+        review_ratings: db.get_review_ratings(),
+    }))
+}
 
-    None
+pub fn parse_review_rating(body: &[u8]) -> Result<PostReviewRatingRequest, ApiError> {
+    serde_json::from_slice(body).map_err(ApiError::InvalidBody)
+}
+
+fn post_review_rating<'a>(request: &Request, db: &Mutex<Database>) -> Result<Response<'a>, ApiError> {
+    let request_body = parse_review_rating(request.body.as_bytes())?;
+    let mut db = timing::timed_lock(db);
+    db.add_review_rating(request_body.id, if request_body.positive { 1 } else { -1 });
+    Ok(json_response(ReviewRatingResponse {
+        id: request_body.id,
+        rating: db.get_review_rating(request_body.id),
+    }))
+}
+
+fn get_visits<'a>(request: &Request, db: &Mutex<Database>) -> Result<Response<'a>, ApiError> {
+    let mut db = timing::timed_lock(db);
+    db.add_visit(&request.client_ip());
+    Ok(json_response(VisitsResponse {
+        visits: db.get_visits(),
+    }))
+}
+
+/// Answers everything under `/api/`, unknown routes included.
+pub fn route<'a>(request: &Request, db: Arc<Mutex<Database>>) -> Response<'a> {
+    let result = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/api/review_ratings") => get_review_ratings(&db),
+        ("POST", "/api/review_ratings") => post_review_rating(request, &db),
+        ("GET", "/api/visits") => get_visits(request, &db),
+        (_, path) if ROUTES.contains(&path) => Err(ApiError::MethodNotAllowed),
+        _ => Err(ApiError::NotFound),
+    };
+    result.unwrap_or_else(|e| e.response(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn review_rating_never_panics(body in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let _ = parse_review_rating(&body);
+        }
+
+        #[test]
+        fn review_rating_round_trips(id in any::<u8>(), positive in any::<bool>()) {
+            let body = format!(r#"{{"id": {}, "positive": {}}}"#, id, positive);
+            let parsed = parse_review_rating(body.as_bytes()).unwrap();
+            prop_assert_eq!(parsed.id, id);
+            prop_assert_eq!(parsed.positive, positive);
+        }
+
+        #[test]
+        fn review_rating_rejects_ids_out_of_range(id in 256i64..) {
+            let body = format!(r#"{{"id": {}, "positive": true}}"#, id);
+            prop_assert!(matches!(parse_review_rating(body.as_bytes()), Err(ApiError::InvalidBody(_))));
+        }
+    }
 }
@@ -0,0 +1,921 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::access_log::AccessLogFormat;
+use crate::http_utils::content_types;
+use crate::logger::{self, Level};
+use crate::proxy::{IpCidr, ProxyProtocolMode};
+use crate::scheduler::JobClass;
+
+const CONFIG_FILENAME: &str = "site_3ds_config.json";
+
+const DEFAULT_PORT: u16 = 8081;
+const DEFAULT_MIN_WORKERS: usize = 3;
+const DEFAULT_MAX_WORKERS: usize = 5;
+// Threads are scarce on the 3DS, going past this just starves the main loop.
+const MAX_WORKER_COUNT: usize = 8;
+const DEFAULT_WORKER_STACK_SIZE: usize = 64 * 1024;
+// Formatting the log lines and building responses needs at least this much.
+const MIN_WORKER_STACK_SIZE: usize = 16 * 1024;
+const DEFAULT_SCALE_INTERVAL_MS: u64 = 1000;
+const DEFAULT_SCALE_DOWN_PERCENT: u8 = 25;
+const DEFAULT_QUEUE_MAX_SIZE: usize = 100;
+const DEFAULT_READY_QUEUE_PERCENT: u8 = 75;
+const DEFAULT_AGING_MS: u64 = 500;
+const DEFAULT_LARGE_ASSET_BYTES: usize = 256 * 1024;
+const DEFAULT_REQUESTS_PER_SECOND: f32 = 10.0;
+// A cold page load pulls in the index, every book cover and the API calls at once.
+const DEFAULT_REQUEST_BURST: u32 = 40;
+const DEFAULT_API_WRITES_PER_SECOND: f32 = 1.0;
+const DEFAULT_API_WRITE_BURST: u32 = 5;
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 8;
+const DEFAULT_MAX_TRACKED_CLIENTS: usize = 1024;
+const DEFAULT_SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_READ_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_WRITE_TIMEOUT_MS: u64 = 10_000;
+// Well under what the 3DS wifi manages, only clients that have all but stopped reading fall below it.
+const DEFAULT_MIN_BYTES_PER_SECOND: u64 = 512;
+const DEFAULT_MIN_RATE_GRACE_MS: u64 = 10_000;
+const DEFAULT_ACCESS_LOG_FILENAME: &str = "site_3ds_access.log";
+const DEFAULT_ACCESS_LOG_MAX_BYTES: u64 = 1024 * 1024;
+const DEFAULT_ACCESS_LOG_MAX_FILES: usize = 3;
+const DEFAULT_ACCESS_LOG_BUFFER_BYTES: usize = 8 * 1024;
+const DEFAULT_ACCESS_LOG_FLUSH_INTERVAL_MS: u64 = 5000;
+// Everything the Vue build needs is served from here, the inline styles come from its scoped CSS.
+const DEFAULT_CONTENT_SECURITY_POLICY: &str =
+    "default-src 'self'; img-src 'self' data:; style-src 'self' 'unsafe-inline'; object-src 'none'; base-uri 'self'";
+const DEFAULT_REFERRER_POLICY: &str = "strict-origin-when-cross-origin";
+const DEFAULT_PERMISSIONS_POLICY: &str = "camera=(), microphone=(), geolocation=(), payment=()";
+// JSON is never rendered, so nothing it could pull in should be allowed.
+const API_CONTENT_SECURITY_POLICY: &str = "default-src 'none'";
+const DEFAULT_CORS_MAX_AGE_SECONDS: u64 = 600;
+const DEFAULT_LOG_FILENAME: &str = "site_3ds.log";
+const DEFAULT_LOG_MAX_BYTES: u64 = 512 * 1024;
+const DEFAULT_DATABASE_FILENAME: &str = "site_3ds_database.bin";
+const DEFAULT_DATABASE_SAVE_INTERVAL_SECONDS: u64 = 60;
+const DEFAULT_VISIT_HISTORY_MAX_SIZE: usize = 5000;
+
+fn is_positive(value: f32) -> bool {
+    value.is_finite() && value > 0.0
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerMode {
+    /// A reader thread parses requests and a pool of workers answers them.
+    #[default]
+    Threaded,
+    /// Everything happens on the main thread over nonblocking sockets.
+    EventLoop,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub mode: ServerMode,
+    pub port: u16,
+    pub queue_max_size: usize,
+    /// `/readyz` fails once any queue is this full.
+    pub ready_queue_percent: u8,
+    /// Peers allowed to tell us the client address through `Forwarded` or `X-Forwarded-For`.
+    pub trusted_proxies: Vec<IpCidr>,
+    /// Whether trusted proxies send a HAProxy PROXY protocol preamble before the request.
+    pub proxy_protocol: ProxyProtocolMode,
+    /// How long responses already being sent get to finish when exiting.
+    pub shutdown_timeout_ms: u64,
+    /// Adds a `Server-Timing` header to every response, showing where the time went in the browser's
+    /// dev tools.
+    pub server_timing: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            mode: ServerMode::default(),
+            port: DEFAULT_PORT,
+            queue_max_size: DEFAULT_QUEUE_MAX_SIZE,
+            ready_queue_percent: DEFAULT_READY_QUEUE_PERCENT,
+            trusted_proxies: vec![],
+            proxy_protocol: ProxyProtocolMode::default(),
+            shutdown_timeout_ms: DEFAULT_SHUTDOWN_TIMEOUT_MS,
+            server_timing: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClassConfig {
+    /// Relative priority against the other classes when jobs have waited the same amount of time.
+    pub weight: u32,
+    /// Workers that only ever serve this class, on top of the shared pool.
+    pub reserved_workers: usize,
+    /// The most workers this class may occupy at once.
+    pub max_concurrent: Option<usize>,
+}
+
+impl ClassConfig {
+    fn new(weight: u32, reserved_workers: usize, max_concurrent: Option<usize>) -> Self {
+        ClassConfig {
+            weight,
+            reserved_workers,
+            max_concurrent,
+        }
+    }
+}
+
+impl Default for ClassConfig {
+    fn default() -> Self {
+        ClassConfig::new(1, 0, None)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerPoolConfig {
+    /// Shared workers kept around even when there is nothing to do.
+    pub min: usize,
+    pub max: usize,
+    /// Stack size in bytes for the reader and every worker thread.
+    pub stack_size: usize,
+    /// How often the pool is resized.
+    pub scale_interval_ms: u64,
+    /// A worker is stopped when the pool was busy less than this share of the last interval.
+    pub scale_down_percent: u8,
+}
+
+impl Default for WorkerPoolConfig {
+    fn default() -> Self {
+        WorkerPoolConfig {
+            min: DEFAULT_MIN_WORKERS,
+            max: DEFAULT_MAX_WORKERS,
+            stack_size: DEFAULT_WORKER_STACK_SIZE,
+            scale_interval_ms: DEFAULT_SCALE_INTERVAL_MS,
+            scale_down_percent: DEFAULT_SCALE_DOWN_PERCENT,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    pub html: ClassConfig,
+    pub api: ClassConfig,
+    pub asset: ClassConfig,
+    pub media: ClassConfig,
+    /// A queued job gains its weight in priority again every `aging_ms` it waits.
+    pub aging_ms: u64,
+    /// Assets at least this big are scheduled as media.
+    pub large_asset_bytes: usize,
+}
+
+impl SchedulerConfig {
+    pub fn class(&self, class: JobClass) -> &ClassConfig {
+        match class {
+            JobClass::Html => &self.html,
+            JobClass::Api => &self.api,
+            JobClass::Asset => &self.asset,
+            JobClass::Media => &self.media,
+        }
+    }
+
+    fn class_mut(&mut self, class: JobClass) -> &mut ClassConfig {
+        match class {
+            JobClass::Html => &mut self.html,
+            JobClass::Api => &mut self.api,
+            JobClass::Asset => &mut self.asset,
+            JobClass::Media => &mut self.media,
+        }
+    }
+
+    pub fn reserved_workers(&self) -> usize {
+        JobClass::ALL
+            .iter()
+            .map(|class| self.class(*class).reserved_workers)
+            .sum()
+    }
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        // One worker kept for the index page, and media never allowed to take every shared worker. `validate`
+        // moves the media cap with the configured minimum pool.
+        SchedulerConfig {
+            html: ClassConfig::new(8, 1, None),
+            api: ClassConfig::new(4, 0, None),
+            asset: ClassConfig::new(2, 0, None),
+            media: ClassConfig::new(1, 0, Some(DEFAULT_MIN_WORKERS - 1)),
+            aging_ms: DEFAULT_AGING_MS,
+            large_asset_bytes: DEFAULT_LARGE_ASSET_BYTES,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub requests_per_second: f32,
+    pub request_burst: u32,
+    /// Applies on top of the request limit to anything under `/api/` that isn't a GET or HEAD.
+    pub api_writes_per_second: f32,
+    pub api_write_burst: u32,
+    pub max_connections_per_ip: usize,
+    /// Caps the memory used by the token buckets, the least recently seen clients are forgotten first.
+    pub max_tracked_clients: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            requests_per_second: DEFAULT_REQUESTS_PER_SECOND,
+            request_burst: DEFAULT_REQUEST_BURST,
+            api_writes_per_second: DEFAULT_API_WRITES_PER_SECOND,
+            api_write_burst: DEFAULT_API_WRITE_BURST,
+            max_connections_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP,
+            max_tracked_clients: DEFAULT_MAX_TRACKED_CLIENTS,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// How long a client has to send its whole request.
+    pub read_timeout_ms: u64,
+    /// The longest a response may go without a single byte being accepted by the client.
+    pub write_timeout_ms: u64,
+    /// Responses slower than this on average are cut off, 0 turns the check off.
+    pub min_bytes_per_second: u64,
+    /// Time a response gets to get up to speed before the minimum rate applies.
+    pub min_rate_grace_ms: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            read_timeout_ms: DEFAULT_READ_TIMEOUT_MS,
+            write_timeout_ms: DEFAULT_WRITE_TIMEOUT_MS,
+            min_bytes_per_second: DEFAULT_MIN_BYTES_PER_SECOND,
+            min_rate_grace_ms: DEFAULT_MIN_RATE_GRACE_MS,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// Off still prints the short line to the console, it just skips the file.
+    pub enabled: bool,
+    pub filename: String,
+    pub format: AccessLogFormat,
+    /// The file is rotated before it grows past this.
+    pub max_bytes: u64,
+    /// Rotated files kept alongside the current one.
+    pub max_files: usize,
+    /// Lines are held in memory until this fills up or the flush interval passes, to spare the SD card.
+    pub buffer_bytes: usize,
+    pub flush_interval_ms: u64,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            enabled: true,
+            filename: DEFAULT_ACCESS_LOG_FILENAME.to_string(),
+            format: AccessLogFormat::default(),
+            max_bytes: DEFAULT_ACCESS_LOG_MAX_BYTES,
+            max_files: DEFAULT_ACCESS_LOG_MAX_FILES,
+            buffer_bytes: DEFAULT_ACCESS_LOG_BUFFER_BYTES,
+            flush_interval_ms: DEFAULT_ACCESS_LOG_FLUSH_INTERVAL_MS,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityHeadersConfig {
+    pub enabled: bool,
+    pub content_security_policy: Option<String>,
+    /// Added to the CSP as `frame-ancestors`, and sent as `X-Frame-Options` too when it is `'none'` or `'self'`.
+    pub frame_ancestors: Option<String>,
+    /// Sends `X-Content-Type-Options: nosniff`.
+    pub content_type_options: bool,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+    /// Off by default, only set it when the site is reached through a proxy that terminates TLS.
+    pub strict_transport_security: Option<String>,
+    /// Headers to replace by content type prefix, such as `"application/json"` or `"image/"`. An empty value
+    /// removes the header.
+    pub content_types: BTreeMap<String, BTreeMap<String, String>>,
+    /// Paths that get none of these headers, a trailing `*` matches anything starting with the rest.
+    pub skip_routes: Vec<String>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            enabled: true,
+            content_security_policy: Some(DEFAULT_CONTENT_SECURITY_POLICY.to_string()),
+            frame_ancestors: Some("'none'".to_string()),
+            content_type_options: true,
+            referrer_policy: Some(DEFAULT_REFERRER_POLICY.to_string()),
+            permissions_policy: Some(DEFAULT_PERMISSIONS_POLICY.to_string()),
+            strict_transport_security: None,
+            content_types: BTreeMap::from([(
+                content_types::JSON.to_string(),
+                BTreeMap::from([(
+                    "Content-Security-Policy".to_string(),
+                    format!("{}; frame-ancestors 'none'", API_CONTENT_SECURITY_POLICY),
+                )]),
+            )]),
+            skip_routes: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins such as `"https://dashboard.example.com"` allowed to call `/api/*` from the browser, or `"*"` for
+    /// any. CORS is off while this is empty.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers a preflight may ask for.
+    pub allowed_headers: Vec<String>,
+    /// Response headers scripts on the other origin may read, beyond the handful browsers always allow.
+    pub exposed_headers: Vec<String>,
+    /// Lets cookies and HTTP auth through. Not with the `"*"` origin, that would let any site call the API as
+    /// the visitor.
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight.
+    pub max_age_seconds: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec![],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec!["Content-Type".to_string()],
+            exposed_headers: vec![],
+            allow_credentials: false,
+            max_age_seconds: DEFAULT_CORS_MAX_AGE_SECONDS,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: Level,
+    /// Levels for individual modules, such as `"handler": "debug"` or `"access_log": "warn"`, overriding
+    /// `level` for everything under them.
+    pub targets: BTreeMap<String, Level>,
+    /// Whether log lines show on the 3DS screens.
+    pub console: bool,
+    /// Whether log lines are also written to `filename` on the SD card.
+    pub file: bool,
+    pub filename: String,
+    /// The log is moved to `<filename>.1` before it grows past this.
+    pub max_bytes: u64,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: Level::default(),
+            targets: BTreeMap::new(),
+            console: true,
+            file: true,
+            filename: DEFAULT_LOG_FILENAME.to_string(),
+            max_bytes: DEFAULT_LOG_MAX_BYTES,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub filename: String,
+    pub save_interval_seconds: u64,
+    pub visit_history_max_size: usize,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            filename: DEFAULT_DATABASE_FILENAME.to_string(),
+            save_interval_seconds: DEFAULT_DATABASE_SAVE_INTERVAL_SECONDS,
+            visit_history_max_size: DEFAULT_VISIT_HISTORY_MAX_SIZE,
+        }
+    }
+}
+
+fn object_at<'a>(root: &'a mut Value, pointer: &str) -> &'a mut Map<String, Value> {
+    root.pointer_mut(pointer)
+        .and_then(Value::as_object_mut)
+        .expect("fields are only merged into objects")
+}
+
+/// Sets `key` of the object at `parent` (a JSON pointer and the dotted name to report it by) to `value`, as long
+/// as the config still deserializes with it. When it doesn't, an object is merged field by field and an array
+/// keeps the elements that are valid, anything else is left as it was.
+fn merge_field(root: &mut Value, parent: (&str, &str), key: &str, value: Value, report: &mut LoadReport) {
+    let (parent_pointer, parent_name) = parent;
+    let pointer = format!("{}/{}", parent_pointer, key.replace('~', "~0").replace('/', "~1"));
+    let name = if parent_name.is_empty() { key.to_string() } else { format!("{}.{}", parent_name, key) };
+
+    let previous = object_at(root, parent_pointer).insert(key.to_string(), value.clone());
+    let error = match serde_json::from_value::<Config>(root.clone()) {
+        Ok(_) => return,
+        Err(e) => e,
+    };
+    match (value, previous) {
+        (Value::Object(fields), Some(previous @ Value::Object(_))) => {
+            object_at(root, parent_pointer).insert(key.to_string(), previous);
+            for (field, value) in fields {
+                merge_field(root, (&pointer, &name), &field, value, report);
+            }
+        }
+        (Value::Array(items), Some(Value::Array(_))) => {
+            object_at(root, parent_pointer).insert(key.to_string(), Value::Array(vec![]));
+            for (index, item) in items.into_iter().enumerate() {
+                root.pointer_mut(&pointer).and_then(Value::as_array_mut).unwrap().push(item);
+                if let Err(e) = serde_json::from_value::<Config>(root.clone()) {
+                    root.pointer_mut(&pointer).and_then(Value::as_array_mut).unwrap().pop();
+                    report.warn(format!("Ignoring {}[{}]: {e}", name, index));
+                }
+            }
+        }
+        (_, previous) => {
+            let parent = object_at(root, parent_pointer);
+            match previous {
+                Some(previous) => parent.insert(key.to_string(), previous),
+                None => parent.remove(key),
+            };
+            report.warn(format!("Ignoring {}: {error}", name));
+        }
+    }
+}
+
+/// What loading the config had to say, held back until the logger is set up.
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    messages: Vec<(Level, String)>,
+}
+
+impl LoadReport {
+    fn info(&mut self, message: impl Into<String>) {
+        self.messages.push((Level::Info, message.into()));
+    }
+
+    fn warn(&mut self, message: impl Into<String>) {
+        self.messages.push((Level::Warn, message.into()));
+    }
+
+    /// Logs everything under this module, as if it had been logged while loading.
+    pub fn log(self) {
+        for (level, message) in self.messages {
+            logger::log(level, module_path!(), format_args!("{}", message));
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub workers: WorkerPoolConfig,
+    pub scheduler: SchedulerConfig,
+    pub rate_limit: RateLimitConfig,
+    pub timeouts: TimeoutConfig,
+    pub access_log: AccessLogConfig,
+    pub security_headers: SecurityHeadersConfig,
+    pub cors: CorsConfig,
+    pub logging: LoggingConfig,
+    pub database: DatabaseConfig,
+}
+
+impl Config {
+    /// Loads the config from the SD card, falling back to defaults for anything missing or invalid. What was
+    /// wrong with it comes back separately, to be logged once the logger has been set up from the config.
+    pub fn load() -> (Config, LoadReport) {
+        let mut report = LoadReport::default();
+        let mut config = match fs::read_to_string(CONFIG_FILENAME) {
+            Ok(raw) => match Config::parse(&raw, &mut report) {
+                Some(config) => {
+                    report.info(format!("Loaded {}", CONFIG_FILENAME));
+                    config
+                }
+                None => {
+                    report.warn("Using default config");
+                    Config::default()
+                }
+            },
+            Err(_) => {
+                report.info(format!("No {}, using defaults", CONFIG_FILENAME));
+                Config::default()
+            }
+        };
+
+        config.validate(&mut report);
+        (config, report)
+    }
+
+    /// Reads the config out of `raw`, keeping every setting that is valid. One that isn't, such as an unknown
+    /// field, a value of the wrong type or a bad CIDR in `trusted_proxies`, is skipped with a warning and left at
+    /// its default. Only JSON that doesn't parse at all gives `None`.
+    fn parse(raw: &str, report: &mut LoadReport) -> Option<Config> {
+        let user = match serde_json::from_str::<Value>(raw) {
+            Ok(Value::Object(user)) => user,
+            Ok(_) => {
+                report.warn(format!("{} must hold a JSON object", CONFIG_FILENAME));
+                return None;
+            }
+            Err(e) => {
+                report.warn(format!("Error parsing {}: {e}", CONFIG_FILENAME));
+                return None;
+            }
+        };
+
+        let mut merged = serde_json::to_value(Config::default()).expect("the default config serializes");
+        for (key, value) in user {
+            merge_field(&mut merged, ("", ""), &key, value, report);
+        }
+        Some(serde_json::from_value(merged).expect("only fields that deserialize are merged"))
+    }
+
+    fn validate(&mut self, report: &mut LoadReport) {
+        let defaults = Config::default();
+
+        if self.server.port == 0 {
+            report.warn(format!("Invalid port 0, using {}", defaults.server.port));
+            self.server.port = defaults.server.port;
+        }
+        if self.workers.max == 0 || self.workers.max > MAX_WORKER_COUNT {
+            report.warn(format!("max workers must be 1-{}, using {}", MAX_WORKER_COUNT, defaults.workers.max));
+            self.workers.max = defaults.workers.max;
+        }
+        if self.workers.min == 0 || self.workers.min > self.workers.max {
+            let min = defaults.workers.min.min(self.workers.max);
+            report.warn(format!("min workers must be 1-{}, using {}", self.workers.max, min));
+            self.workers.min = min;
+        }
+        if self.workers.stack_size < MIN_WORKER_STACK_SIZE {
+            report.warn(format!(
+                "stack_size must be at least {}, using {}",
+                MIN_WORKER_STACK_SIZE, defaults.workers.stack_size
+            ));
+            self.workers.stack_size = defaults.workers.stack_size;
+        }
+        if self.workers.scale_interval_ms == 0 {
+            report.warn(format!("scale_interval_ms must be > 0, using {}", defaults.workers.scale_interval_ms));
+            self.workers.scale_interval_ms = defaults.workers.scale_interval_ms;
+        }
+        if self.workers.scale_down_percent > 100 {
+            report.warn(format!("scale_down_percent must be 0-100, using {}", defaults.workers.scale_down_percent));
+            self.workers.scale_down_percent = defaults.workers.scale_down_percent;
+        }
+        if self.server.queue_max_size == 0 {
+            report.warn(format!("queue_max_size must be > 0, using {}", defaults.server.queue_max_size));
+            self.server.queue_max_size = defaults.server.queue_max_size;
+        }
+        if self.server.ready_queue_percent == 0 || self.server.ready_queue_percent > 100 {
+            report.warn(format!(
+                "ready_queue_percent must be 1-100, using {}",
+                defaults.server.ready_queue_percent
+            ));
+            self.server.ready_queue_percent = defaults.server.ready_queue_percent;
+        }
+        if self.server.proxy_protocol != ProxyProtocolMode::Off && self.server.trusted_proxies.is_empty() {
+            report.warn("proxy_protocol needs trusted_proxies, no peer will be allowed to send it");
+        }
+
+        for class in JobClass::ALL {
+            let default_class = defaults.scheduler.class(class).clone();
+            let class_config = self.scheduler.class_mut(class);
+            if class_config.weight == 0 {
+                report.warn(format!("{} weight must be > 0, using {}", class.name(), default_class.weight));
+                class_config.weight = default_class.weight;
+            }
+            if class_config.max_concurrent == Some(0) {
+                report.warn(format!("{} max_concurrent must be > 0, ignoring it", class.name()));
+                class_config.max_concurrent = None;
+            }
+        }
+        if self.scheduler.reserved_workers() > MAX_WORKER_COUNT {
+            report.warn(format!(
+                "Too many reserved workers, using {} for html only",
+                defaults.scheduler.html.reserved_workers
+            ));
+            for class in JobClass::ALL {
+                self.scheduler.class_mut(class).reserved_workers =
+                    defaults.scheduler.class(class).reserved_workers;
+            }
+        }
+        // The default cap on media is for the default pool, it has to follow the pool actually configured.
+        if self.scheduler.media.max_concurrent == defaults.scheduler.media.max_concurrent {
+            self.scheduler.media.max_concurrent = Some(self.workers.min.saturating_sub(1).max(1));
+        }
+        if self.scheduler.aging_ms == 0 {
+            report.warn(format!("aging_ms must be > 0, using {}", defaults.scheduler.aging_ms));
+            self.scheduler.aging_ms = defaults.scheduler.aging_ms;
+        }
+
+        if !is_positive(self.rate_limit.requests_per_second) {
+            report.warn(format!(
+                "requests_per_second must be > 0, using {}",
+                defaults.rate_limit.requests_per_second
+            ));
+            self.rate_limit.requests_per_second = defaults.rate_limit.requests_per_second;
+        }
+        if self.rate_limit.request_burst == 0 {
+            report.warn(format!("request_burst must be > 0, using {}", defaults.rate_limit.request_burst));
+            self.rate_limit.request_burst = defaults.rate_limit.request_burst;
+        }
+        if !is_positive(self.rate_limit.api_writes_per_second) {
+            report.warn(format!(
+                "api_writes_per_second must be > 0, using {}",
+                defaults.rate_limit.api_writes_per_second
+            ));
+            self.rate_limit.api_writes_per_second = defaults.rate_limit.api_writes_per_second;
+        }
+        if self.rate_limit.api_write_burst == 0 {
+            report.warn(format!("api_write_burst must be > 0, using {}", defaults.rate_limit.api_write_burst));
+            self.rate_limit.api_write_burst = defaults.rate_limit.api_write_burst;
+        }
+        if self.rate_limit.max_connections_per_ip == 0 {
+            report.warn(format!(
+                "max_connections_per_ip must be > 0, using {}",
+                defaults.rate_limit.max_connections_per_ip
+            ));
+            self.rate_limit.max_connections_per_ip = defaults.rate_limit.max_connections_per_ip;
+        }
+        if self.rate_limit.max_tracked_clients == 0 {
+            report.warn(format!(
+                "max_tracked_clients must be > 0, using {}",
+                defaults.rate_limit.max_tracked_clients
+            ));
+            self.rate_limit.max_tracked_clients = defaults.rate_limit.max_tracked_clients;
+        }
+
+        if self.timeouts.read_timeout_ms == 0 {
+            report.warn(format!("read_timeout_ms must be > 0, using {}", defaults.timeouts.read_timeout_ms));
+            self.timeouts.read_timeout_ms = defaults.timeouts.read_timeout_ms;
+        }
+        if self.timeouts.write_timeout_ms == 0 {
+            report.warn(format!("write_timeout_ms must be > 0, using {}", defaults.timeouts.write_timeout_ms));
+            self.timeouts.write_timeout_ms = defaults.timeouts.write_timeout_ms;
+        }
+
+        if self.access_log.filename.trim().is_empty() {
+            report.warn(format!("Empty access log filename, using {}", defaults.access_log.filename));
+            self.access_log.filename = defaults.access_log.filename.clone();
+        }
+        if self.access_log.max_bytes == 0 {
+            report.warn(format!("access log max_bytes must be > 0, using {}", defaults.access_log.max_bytes));
+            self.access_log.max_bytes = defaults.access_log.max_bytes;
+        }
+
+        if self.cors.allowed_methods.is_empty() {
+            report.warn(format!("CORS allowed_methods is empty, using {}", defaults.cors.allowed_methods.join(", ")));
+            self.cors.allowed_methods = defaults.cors.allowed_methods.clone();
+        }
+        for origin in &mut self.cors.allowed_origins {
+            // Browsers send the origin without a trailing slash, so one here would never match.
+            if origin.ends_with('/') {
+                let trimmed = origin.trim_end_matches('/').to_string();
+                report.warn(format!("CORS origin {} has a trailing slash, using {}", origin, trimmed));
+                *origin = trimmed;
+            }
+        }
+        if self.cors.allow_credentials && self.cors.allowed_origins.iter().any(|origin| origin == "*") {
+            report.warn("CORS allow_credentials can't be used with the \"*\" origin, turning it off");
+            self.cors.allow_credentials = false;
+        }
+
+        if self.logging.filename.trim().is_empty() {
+            report.warn(format!("Empty log filename, using {}", defaults.logging.filename));
+            self.logging.filename = defaults.logging.filename.clone();
+        }
+        if self.logging.max_bytes == 0 {
+            report.warn(format!("log max_bytes must be > 0, using {}", defaults.logging.max_bytes));
+            self.logging.max_bytes = defaults.logging.max_bytes;
+        }
+
+        if self.database.filename.trim().is_empty() {
+            report.warn(format!("Empty database filename, using {}", defaults.database.filename));
+            self.database.filename = defaults.database.filename.clone();
+        }
+        if self.database.save_interval_seconds == 0 {
+            report.warn(format!(
+                "save_interval_seconds must be > 0, using {}",
+                defaults.database.save_interval_seconds
+            ));
+            self.database.save_interval_seconds = defaults.database.save_interval_seconds;
+        }
+        if self.database.visit_history_max_size == 0 {
+            report.warn(format!(
+                "visit_history_max_size must be > 0, using {}",
+                defaults.database.visit_history_max_size
+            ));
+            self.database.visit_history_max_size = defaults.database.visit_history_max_size;
+        }
+    }
+
+    /// Short summary sized for the bottom screen.
+    pub fn print(&self) {
+        println!("Mode: {:?}", self.server.mode);
+        println!("Port: {}", self.server.port);
+        println!(
+            "Workers: {}-{} + {} reserved",
+            self.workers.min,
+            self.workers.max,
+            self.scheduler.reserved_workers()
+        );
+        println!("Queue max size: {}", self.server.queue_max_size);
+        for trusted_proxy in &self.server.trusted_proxies {
+            println!("Trusted proxy: {}", trusted_proxy);
+        }
+        println!("PROXY protocol: {:?}", self.server.proxy_protocol);
+        if self.rate_limit.enabled {
+            println!(
+                "Rate limit: {}/s burst {}",
+                self.rate_limit.requests_per_second, self.rate_limit.request_burst
+            );
+        } else {
+            println!("Rate limit: off");
+        }
+        println!(
+            "Timeouts: read {}ms write {}ms min {}B/s",
+            self.timeouts.read_timeout_ms, self.timeouts.write_timeout_ms, self.timeouts.min_bytes_per_second
+        );
+        if self.access_log.enabled {
+            println!("Access log: {} ({:?})", self.access_log.filename, self.access_log.format);
+        } else {
+            println!("Access log: off");
+        }
+        println!("Security headers: {}", if self.security_headers.enabled { "on" } else { "off" });
+        if self.cors.allowed_origins.is_empty() {
+            println!("CORS: off");
+        } else {
+            println!("CORS: {}", self.cors.allowed_origins.join(", "));
+        }
+        println!("Log level: {:?}", self.logging.level);
+        println!("Database: {}", self.database.filename);
+        println!("Save interval: {}s", self.database.save_interval_seconds);
+        println!("Visit history max: {}", self.database.visit_history_max_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads `raw` the way `load` would, returning the warnings along with the config.
+    fn load(raw: &str) -> (Config, Vec<String>) {
+        let mut report = LoadReport::default();
+        let mut config = Config::parse(raw, &mut report).unwrap_or_default();
+        config.validate(&mut report);
+        let warnings = report
+            .messages
+            .into_iter()
+            .filter(|(level, _)| *level == Level::Warn)
+            .map(|(_, message)| message)
+            .collect();
+        (config, warnings)
+    }
+
+    #[test]
+    fn missing_fields_keep_their_defaults() {
+        let (config, warnings) =
+            load(r#"{"server": {"port": 9000}, "cors": {"allowed_origins": ["https://a.example"]}}"#);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.queue_max_size, DEFAULT_QUEUE_MAX_SIZE);
+        assert_eq!(config.cors.allowed_origins, ["https://a.example"]);
+        assert_eq!(config.cors.allowed_methods, CorsConfig::default().allowed_methods);
+        assert_eq!(config.workers.max, DEFAULT_MAX_WORKERS);
+
+        let (config, warnings) = load("{}");
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(config.server.port, DEFAULT_PORT);
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let (config, warnings) = load(r#"{"server": {"port": 9000, "prot": 1}, "nope": true, "workers": {"max": 6}}"#);
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.workers.max, 6);
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        // Fields are gone through in alphabetical order.
+        assert!(warnings[0].starts_with("Ignoring nope:"), "{}", warnings[0]);
+        assert!(warnings[1].starts_with("Ignoring server.prot:"), "{}", warnings[1]);
+    }
+
+    #[test]
+    fn an_invalid_cidr_only_drops_itself() {
+        let (config, warnings) = load(
+            r#"{"server": {"port": 9000, "trusted_proxies": ["10.0.0.0/8", "10.0.0.0/33", "fd00::/8", "nope"]}}"#,
+        );
+        assert_eq!(config.server.port, 9000);
+        let trusted: Vec<String> = config.server.trusted_proxies.iter().map(|cidr| cidr.to_string()).collect();
+        assert_eq!(trusted, ["10.0.0.0/8", "fd00::/8"]);
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert!(warnings[0].starts_with("Ignoring server.trusted_proxies[1]:"), "{}", warnings[0]);
+        assert!(warnings[1].starts_with("Ignoring server.trusted_proxies[3]:"), "{}", warnings[1]);
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_skipped() {
+        let (config, warnings) = load(
+            r#"{
+                "server": {"port": "eighty", "mode": "event_loop"},
+                "logging": {"targets": {"handler": "debug", "api": "loud"}}
+            }"#,
+        );
+        assert_eq!(config.server.port, DEFAULT_PORT);
+        assert_eq!(config.server.mode, ServerMode::EventLoop);
+        assert_eq!(config.logging.targets, BTreeMap::from([("handler".to_string(), Level::Debug)]));
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert!(warnings[0].starts_with("Ignoring logging.targets.api:"), "{}", warnings[0]);
+        assert!(warnings[1].starts_with("Ignoring server.port:"), "{}", warnings[1]);
+    }
+
+    #[test]
+    fn zero_and_negative_limits_use_the_defaults() {
+        let (config, warnings) = load(
+            r#"{
+                "rate_limit": {
+                    "requests_per_second": -1, "request_burst": 0, "api_write_burst": -5, "max_connections_per_ip": 2
+                },
+                "timeouts": {"read_timeout_ms": 0, "write_timeout_ms": -100, "min_bytes_per_second": 0},
+                "workers": {"min": 0, "max": 0},
+                "server": {"queue_max_size": 0}
+            }"#,
+        );
+        let defaults = Config::default();
+        assert_eq!(config.rate_limit.requests_per_second, defaults.rate_limit.requests_per_second);
+        assert_eq!(config.rate_limit.request_burst, defaults.rate_limit.request_burst);
+        assert_eq!(config.rate_limit.api_write_burst, defaults.rate_limit.api_write_burst);
+        assert_eq!(config.rate_limit.max_connections_per_ip, 2);
+        assert_eq!(config.timeouts.read_timeout_ms, defaults.timeouts.read_timeout_ms);
+        assert_eq!(config.timeouts.write_timeout_ms, defaults.timeouts.write_timeout_ms);
+        // Zero turns the minimum transfer rate off.
+        assert_eq!(config.timeouts.min_bytes_per_second, 0);
+        assert_eq!(config.workers.max, defaults.workers.max);
+        assert_eq!(config.workers.min, defaults.workers.min);
+        assert_eq!(config.server.queue_max_size, defaults.server.queue_max_size);
+        assert_eq!(warnings.len(), 8, "{:?}", warnings);
+    }
+
+    #[test]
+    fn unparseable_files_use_the_defaults() {
+        let mut report = LoadReport::default();
+        assert!(Config::parse(r#"{"server": {"port": 9000"#, &mut report).is_none());
+        assert!(Config::parse("[1, 2]", &mut report).is_none());
+        assert_eq!(report.messages.len(), 2);
+    }
+
+    #[test]
+    fn the_default_media_cap_follows_the_worker_pool() {
+        let (config, warnings) = load(r#"{"workers": {"min": 5, "max": 8}}"#);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(config.scheduler.media.max_concurrent, Some(4));
+
+        // A single shared worker can't be kept from media, growing the pool has to make room for the rest.
+        let (config, _) = load(r#"{"workers": {"min": 1}}"#);
+        assert_eq!(config.scheduler.media.max_concurrent, Some(1));
+
+        let (config, _) = load(r#"{"workers": {"min": 1}, "scheduler": {"media": {"max_concurrent": 3}}}"#);
+        assert_eq!(config.scheduler.media.max_concurrent, Some(3));
+        let (config, _) = load(r#"{"workers": {"min": 1}, "scheduler": {"media": {"max_concurrent": null}}}"#);
+        assert_eq!(config.scheduler.media.max_concurrent, None);
+    }
+
+    #[test]
+    fn cors_credentials_are_refused_with_a_wildcard_origin() {
+        let (config, warnings) =
+            load(r#"{"cors": {"allowed_origins": ["https://a.example", "*"], "allow_credentials": true}}"#);
+        assert!(!config.cors.allow_credentials);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].starts_with("CORS allow_credentials"), "{}", warnings[0]);
+
+        let (config, warnings) =
+            load(r#"{"cors": {"allowed_origins": ["https://a.example"], "allow_credentials": true}}"#);
+        assert!(config.cors.allow_credentials);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn cors_origins_lose_their_trailing_slash() {
+        let (config, warnings) = load(r#"{"cors": {"allowed_origins": ["https://a.example/"]}}"#);
+        assert_eq!(config.cors.allowed_origins, ["https://a.example"]);
+        assert_eq!(warnings, ["CORS origin https://a.example/ has a trailing slash, using https://a.example"]);
+    }
+}
@@ -0,0 +1,285 @@
+use crate::api;
+use crate::config::CorsConfig;
+use crate::http_utils::{Request, Response};
+use crate::middleware::Middleware;
+
+/// Cross origin access to the JSON API, so pages served from somewhere else can read it.
+pub struct Cors {
+    config: CorsConfig,
+}
+
+impl Cors {
+    pub fn new(config: &CorsConfig) -> Self {
+        Self {
+            config: config.clone(),
+        }
+    }
+
+    fn applies_to(request: &Request) -> bool {
+        request.path.starts_with("/api/")
+    }
+
+    fn enabled(&self) -> bool {
+        !self.config.allowed_origins.is_empty()
+    }
+
+    /// The value to send back in `Access-Control-Allow-Origin`, if `origin` may see the response.
+    fn allowed_origin<'a>(&'a self, origin: &'a str) -> Option<&'a str> {
+        // Never echoed back, browsers refuse credentials with `*`, which is what keeps a wildcard safe.
+        if self.config.allowed_origins.iter().any(|allowed| allowed == "*") {
+            return Some("*");
+        }
+        self.config
+            .allowed_origins
+            .iter()
+            .find(|allowed| allowed.eq_ignore_ascii_case(origin))
+            .map(|_| origin)
+    }
+
+    /// The configured methods `path` actually answers, so a preflight never promises one it would refuse.
+    fn allowed_methods(&self, path: &str) -> Vec<&str> {
+        self.config
+            .allowed_methods
+            .iter()
+            .filter(|allowed| api::allowed_methods(path).iter().any(|method| method.eq_ignore_ascii_case(allowed)))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Every header in a comma separated `Access-Control-Request-Headers` list is one we allow.
+    fn headers_allowed(&self, headers: &str) -> bool {
+        headers
+            .split(',')
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .all(|header| self.config.allowed_headers.iter().any(|allowed| allowed.eq_ignore_ascii_case(header)))
+    }
+}
+
+impl Middleware for Cors {
+    /// Answers `OPTIONS` for the API routes, including CORS preflights. Anything else is left to be routed.
+    fn before(&self, request: &Request) -> Option<Response<'static>> {
+        if request.method != "OPTIONS" || !api::ROUTES.contains(&request.path.as_str()) {
+            return None;
+        }
+
+        let mut response = Response::new();
+        response.status = 204;
+        let allowed_methods = self.allowed_methods(&request.path);
+        response.headers.push(format!("Allow: OPTIONS, {}", allowed_methods.join(", ")));
+        if !self.enabled() {
+            return Some(response);
+        }
+        response.headers.push("Vary: Origin, Access-Control-Request-Method, Access-Control-Request-Headers".to_string());
+
+        // A preflight the origin, method or headers fail gets no `Access-Control-*` headers, which the browser
+        // takes as a refusal.
+        let (Some(origin), Some(method)) =
+            (request.get_header("Origin"), request.get_header("Access-Control-Request-Method"))
+        else {
+            return Some(response);
+        };
+        let requested_headers = request.get_header("Access-Control-Request-Headers").unwrap_or_default();
+        let Some(allowed_origin) = self.allowed_origin(&origin) else {
+            return Some(response);
+        };
+        let method_allowed = allowed_methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(&method));
+        if !method_allowed || !self.headers_allowed(&requested_headers) {
+            return Some(response);
+        }
+
+        response.headers.push(format!("Access-Control-Allow-Origin: {}", allowed_origin));
+        response.headers.push(format!("Access-Control-Allow-Methods: {}", allowed_methods.join(", ")));
+        if !self.config.allowed_headers.is_empty() {
+            response.headers.push(format!("Access-Control-Allow-Headers: {}", self.config.allowed_headers.join(", ")));
+        }
+        if self.config.allow_credentials {
+            response.headers.push("Access-Control-Allow-Credentials: true".to_string());
+        }
+        response.headers.push(format!("Access-Control-Max-Age: {}", self.config.max_age_seconds));
+        Some(response)
+    }
+
+    /// Adds the `Access-Control-*` headers to an API response for an allowed origin.
+    fn after(&self, request: &Request, response: &mut Response) {
+        if !self.enabled() || !Self::applies_to(request) || request.method == "OPTIONS" {
+            return;
+        }
+
+        // Caches have to keep responses for different origins apart, even ones that got no CORS headers.
+        response.headers.push("Vary: Origin".to_string());
+        let Some(origin) = request.get_header("Origin") else {
+            return;
+        };
+        let Some(allowed_origin) = self.allowed_origin(&origin) else {
+            return;
+        };
+        response.headers.push(format!("Access-Control-Allow-Origin: {}", allowed_origin));
+        if self.config.allow_credentials {
+            response.headers.push("Access-Control-Allow-Credentials: true".to_string());
+        }
+        if !self.config.exposed_headers.is_empty() {
+            response.headers.push(format!("Access-Control-Expose-Headers: {}", self.config.exposed_headers.join(", ")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str]) -> Cors {
+        Cors::new(&CorsConfig {
+            allowed_origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            ..CorsConfig::default()
+        })
+    }
+
+    fn request(method: &str, path: &str, headers: &[&str]) -> Request {
+        let mut raw = format!("{} {} HTTP/1.1\r\n", method, path);
+        for header in headers {
+            raw.push_str(header);
+            raw.push_str("\r\n");
+        }
+        raw.push_str("\r\n");
+        Request::parse(raw.as_bytes()).unwrap()
+    }
+
+    fn after(cors: &Cors, request: &Request) -> Vec<String> {
+        let mut response = Response::new();
+        cors.after(request, &mut response);
+        response.headers
+    }
+
+    fn preflight(cors: &Cors, origin: &str, method: &str, headers: &str) -> Vec<String> {
+        let origin = format!("Origin: {}", origin);
+        let method = format!("Access-Control-Request-Method: {}", method);
+        let headers = format!("Access-Control-Request-Headers: {}", headers);
+        let request = request("OPTIONS", "/api/review_ratings", &[&origin, &method, &headers]);
+        let response = cors.before(&request).unwrap();
+        assert_eq!(response.status, 204);
+        response.headers
+    }
+
+    fn allows_origin(headers: &[String]) -> Option<&str> {
+        headers.iter().find_map(|header| header.strip_prefix("Access-Control-Allow-Origin: "))
+    }
+
+    #[test]
+    fn only_listed_origins_are_allowed() {
+        let cors = cors(&["https://a.example", "https://b.example"]);
+        let allowed = request("GET", "/api/visits", &["Origin: https://B.example"]);
+        assert_eq!(allows_origin(&after(&cors, &allowed)), Some("https://B.example"));
+
+        for origin in ["https://c.example", "https://a.example.evil", "http://a.example", "null"] {
+            let refused = request("GET", "/api/visits", &[&format!("Origin: {}", origin)]);
+            assert_eq!(allows_origin(&after(&cors, &refused)), None, "{}", origin);
+        }
+    }
+
+    #[test]
+    fn wildcard_never_echoes_the_origin() {
+        let request = request("GET", "/api/visits", &["Origin: https://a.example"]);
+        assert_eq!(allows_origin(&after(&cors(&["*"]), &request)), Some("*"));
+
+        // Config::validate turns credentials off with a wildcard, this only matters if that were skipped.
+        let credentials = Cors::new(&CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allow_credentials: true,
+            ..CorsConfig::default()
+        });
+        assert_eq!(allows_origin(&after(&credentials, &request)), Some("*"));
+    }
+
+    #[test]
+    fn api_responses_vary_on_origin() {
+        let cors = cors(&["https://a.example"]);
+        assert_eq!(after(&cors, &request("GET", "/api/visits", &[])), ["Vary: Origin"]);
+        let refused = request("GET", "/api/visits", &["Origin: https://c.example"]);
+        assert_eq!(after(&cors, &refused), ["Vary: Origin"]);
+        let allowed = request("GET", "/api/visits", &["Origin: https://a.example"]);
+        assert_eq!(after(&cors, &allowed), ["Vary: Origin", "Access-Control-Allow-Origin: https://a.example"]);
+    }
+
+    #[test]
+    fn off_or_outside_the_api_adds_nothing() {
+        let request_from = |path| request("GET", path, &["Origin: https://a.example"]);
+        assert!(after(&cors(&[]), &request_from("/api/visits")).is_empty());
+        assert!(after(&cors(&["https://a.example"]), &request_from("/")).is_empty());
+        assert!(after(&cors(&["*"]), &request_from("/books/HNI_0002.jpg")).is_empty());
+    }
+
+    #[test]
+    fn exposed_headers_are_listed() {
+        let cors = Cors::new(&CorsConfig {
+            allowed_origins: vec!["https://a.example".to_string()],
+            exposed_headers: vec!["Retry-After".to_string(), "Server-Timing".to_string()],
+            ..CorsConfig::default()
+        });
+        let headers = after(&cors, &request("POST", "/api/review_ratings", &["Origin: https://a.example"]));
+        assert!(headers.contains(&"Access-Control-Expose-Headers: Retry-After, Server-Timing".to_string()));
+    }
+
+    #[test]
+    fn allowed_preflight() {
+        let cors = cors(&["https://a.example"]);
+        assert_eq!(
+            preflight(&cors, "https://a.example", "POST", "content-type"),
+            [
+                "Allow: OPTIONS, GET, POST",
+                "Vary: Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+                "Access-Control-Allow-Origin: https://a.example",
+                "Access-Control-Allow-Methods: GET, POST",
+                "Access-Control-Allow-Headers: Content-Type",
+                "Access-Control-Max-Age: 600",
+            ]
+        );
+    }
+
+    #[test]
+    fn preflights_only_offer_the_methods_the_route_answers() {
+        let cors = Cors::new(&CorsConfig {
+            allowed_origins: vec!["https://a.example".to_string()],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string(), "DELETE".to_string()],
+            ..CorsConfig::default()
+        });
+        let headers = preflight(&cors, "https://a.example", "POST", "content-type");
+        assert!(headers.contains(&"Allow: OPTIONS, GET, POST".to_string()), "{:?}", headers);
+        assert!(headers.contains(&"Access-Control-Allow-Methods: GET, POST".to_string()), "{:?}", headers);
+
+        let request = request(
+            "OPTIONS",
+            "/api/visits",
+            &["Origin: https://a.example", "Access-Control-Request-Method: POST"],
+        );
+        let headers = cors.before(&request).unwrap().headers;
+        assert_eq!(headers[0], "Allow: OPTIONS, GET");
+        assert_eq!(allows_origin(&headers), None);
+    }
+
+    #[test]
+    fn refused_preflights_get_no_access_control_headers() {
+        let cors = cors(&["https://a.example"]);
+        let refused = [
+            preflight(&cors, "https://c.example", "POST", "content-type"),
+            preflight(&cors, "https://a.example", "DELETE", "content-type"),
+            preflight(&cors, "https://a.example", "POST", "content-type, x-secret"),
+        ];
+        for headers in refused {
+            assert!(!headers.iter().any(|header| header.starts_with("Access-Control-")), "{:?}", headers);
+            assert!(headers.iter().any(|header| header.starts_with("Vary: Origin")), "{:?}", headers);
+        }
+    }
+
+    #[test]
+    fn options_is_answered_for_api_routes_only() {
+        let cors = cors(&[]);
+        let response = cors.before(&request("OPTIONS", "/api/visits", &[])).unwrap();
+        assert_eq!(response.headers, ["Allow: OPTIONS, GET"]);
+        let response = cors.before(&request("OPTIONS", "/api/review_ratings", &[])).unwrap();
+        assert_eq!(response.headers, ["Allow: OPTIONS, GET, POST"]);
+        assert!(cors.before(&request("OPTIONS", "/api/no_such_route", &[])).is_none());
+        assert!(cors.before(&request("OPTIONS", "/", &[])).is_none());
+        assert!(cors.before(&request("GET", "/api/visits", &[])).is_none());
+    }
+}
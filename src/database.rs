@@ -1,10 +1,28 @@
-use std::{collections::HashMap, fs::File, io::BufReader, net::IpAddr, time::SystemTime, u32};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    io::BufReader,
+    net::IpAddr,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, SystemTime},
+    u32,
+};
 
 use serde::{Deserialize, Serialize};
 
-const DATABASE_SAVE_INTERVAL_SECONDS: u64 = 60;
-const VISIT_HISTORY_MAX_SIZE: usize = 5000;
-const DATABASE_FILENAME: &str = "site_3ds_database.bin";
+use crate::config::DatabaseConfig;
+use crate::logger::{debug, error, info, warn};
+
+/// Locks `db`, carrying on if a thread panicked while holding it. Every change keeps the database consistent
+/// from one statement to the next, so the worst a panic can leave behind is a visit that wasn't counted.
+pub fn lock(db: &Mutex<Database>) -> MutexGuard<'_, Database> {
+    db.lock().unwrap_or_else(|poisoned| {
+        warn!("Recovering the database after a panic");
+        db.clear_poison();
+        poisoned.into_inner()
+    })
+}
 
 #[derive(Serialize, Deserialize, Clone, Copy, Hash, Eq, PartialEq)]
 pub enum StoredIp {
@@ -26,21 +44,37 @@ pub struct Database {
     visits: u64,
     #[serde(skip)]
     dirty_start: Option<SystemTime>,
+    #[serde(skip)]
+    config: DatabaseConfig,
+    #[serde(skip)]
+    last_save_attempt: Option<SystemTime>,
+    #[serde(skip)]
+    last_save_failed: bool,
+    #[serde(skip)]
+    save_failures: u64,
 }
 
 impl Default for Database {
     fn default() -> Self {
+        Database::with_config(DatabaseConfig::default())
+    }
+}
+
+impl Database {
+    fn with_config(config: DatabaseConfig) -> Self {
         Database {
             review_ratings: HashMap::with_capacity(u8::MAX as usize),
-            visit_history: HashMap::with_capacity(VISIT_HISTORY_MAX_SIZE),
+            visit_history: HashMap::with_capacity(config.visit_history_max_size),
             least_visitor: None,
             visits: 0,
             dirty_start: None,
+            config,
+            last_save_attempt: None,
+            last_save_failed: false,
+            save_failures: 0,
         }
     }
-}
 
-impl Database {
     fn set_dirty(&mut self) {
         if self.dirty_start.is_none() {
             self.dirty_start = Some(SystemTime::now());
@@ -84,7 +118,7 @@ impl Database {
                 *entry
             }
             None => {
-                if self.visit_history.len() + 1 > VISIT_HISTORY_MAX_SIZE
+                if self.visit_history.len() + 1 > self.config.visit_history_max_size
                     && self.least_visitor.is_some()
                 {
                     self.visit_history.remove(&self.least_visitor.unwrap().ip);
@@ -130,30 +164,81 @@ impl Database {
         self.set_dirty();
     }
 
-    pub fn new() -> Database {
-        if let Ok(file) = std::fs::File::open(DATABASE_FILENAME) {
+    pub fn new(config: &DatabaseConfig) -> Database {
+        if let Ok(file) = std::fs::File::open(&config.filename) {
             let reader = std::io::BufReader::new(file);
-            if let Ok(db) = bincode::deserialize_from::<BufReader<File>, Database>(reader) {
-                println!("Loading existing database");
+            if let Ok(mut db) = bincode::deserialize_from::<BufReader<File>, Database>(reader) {
+                info!("Loading existing database");
+                db.config = config.clone();
                 return db;
             };
         }
 
-        println!("Creating new database");
-        Database::default()
+        info!("Creating new database");
+        Database::with_config(config.clone())
     }
 
-    pub fn step(&mut self) {
-        if let Some(dirty) = self.dirty_start {
-            if dirty.elapsed().unwrap().as_secs() > DATABASE_SAVE_INTERVAL_SECONDS {
-                {
-                    let file = std::fs::File::create(DATABASE_FILENAME).unwrap();
-                    let writer = std::io::BufWriter::new(file);
-                    bincode::serialize_into(writer, &self).unwrap();
-                }
-                println!("Database saved");
+    pub fn dirty_age(&self) -> Option<Duration> {
+        self.dirty_start.map(|dirty| dirty.elapsed().unwrap_or_default())
+    }
+
+    pub fn save_failures(&self) -> u64 {
+        self.save_failures
+    }
+
+    /// The database is ready as long as the last attempt to write it to the SD card worked. It is only read
+    /// from the card at startup, so this doesn't check the file can still be loaded, only that what is in
+    /// memory is still being kept.
+    pub fn is_ready(&self) -> bool {
+        !self.last_save_failed
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(&self.config.filename)?;
+        let writer = std::io::BufWriter::new(file);
+        bincode::serialize_into(writer, &self)?;
+        Ok(())
+    }
+
+    fn save_due(&self, since: Option<SystemTime>) -> bool {
+        match since {
+            Some(since) => since.elapsed().unwrap_or_default().as_secs() > self.config.save_interval_seconds,
+            None => true,
+        }
+    }
+
+    fn try_save(&mut self) {
+        self.last_save_attempt = Some(SystemTime::now());
+        match self.save() {
+            Ok(_) => {
+                debug!("Database saved");
                 self.dirty_start = None;
+                self.last_save_failed = false;
             }
+            Err(e) => {
+                error!("Error saving database: {e}");
+                self.save_failures += 1;
+                self.last_save_failed = true;
+            }
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.dirty_start.is_none() || !self.save_due(self.dirty_start) {
+            return;
+        }
+        // Don't hammer the SD card every frame after a failed save.
+        if !self.save_due(self.last_save_attempt) {
+            return;
+        }
+
+        self.try_save();
+    }
+
+    /// Saves any unsaved changes straight away, ignoring the save interval.
+    pub fn flush(&mut self) {
+        if self.dirty_start.is_some() {
+            self.try_save();
         }
     }
 }
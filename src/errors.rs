@@ -0,0 +1,192 @@
+use serde::Serialize;
+
+use crate::handler::ERROR_PAGES;
+use crate::http_utils::{Request, Response, ResponseBody, content_types, status_to_message, Unparsed};
+
+#[derive(Serialize)]
+struct ErrorEnvelope<'a> {
+    error: ErrorBody<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+}
+
+/// How much an `Accept` header wants `media_type`, from the most specific range covering it, along with how
+/// specific that range was: 2 for the type itself, 1 for `type/*` and 0 for `*/*`.
+fn accept_quality(accept: &str, media_type: &str) -> Option<(f32, u8)> {
+    let (kind, _) = media_type.split_once('/')?;
+    let mut best: Option<(f32, u8)> = None;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let range = params.next().unwrap_or_default().trim();
+        let specificity = if range.eq_ignore_ascii_case(media_type) {
+            2
+        } else if range
+            .split_once('/')
+            .is_some_and(|(range_kind, subtype)| subtype == "*" && range_kind.eq_ignore_ascii_case(kind))
+        {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        // A quality that doesn't parse is taken as a refusal rather than a guess.
+        let quality = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .map_or(1.0, |(_, value)| value.trim().parse::<f32>().unwrap_or(0.0));
+        if best.is_none_or(|(_, best)| specificity > best) {
+            best = Some((quality, specificity));
+        }
+    }
+    best
+}
+
+/// Whether errors for `request` should be the JSON envelope rather than an HTML page. Outside the API that
+/// takes an `Accept` header preferring JSON to HTML, a tie going to whichever was asked for more specifically
+/// and then to HTML.
+pub fn wants_json(request: &Request) -> bool {
+    if request.path.starts_with("/api/") {
+        return true;
+    }
+    let Some(accept) = request.get_header("Accept") else {
+        return false;
+    };
+    let json = accept_quality(&accept, content_types::JSON).unwrap_or((0.0, 0));
+    let html = accept_quality(&accept, content_types::HTML).unwrap_or((0.0, 0));
+    json.0 > 0.0 && json > html
+}
+
+/// `{ "error": { "code": ..., "message": ... } }`, where `code` is a short name for programs to match on and
+/// `message` is for people.
+pub fn json<'a>(status: u16, code: &str, message: &str) -> Response<'a> {
+    let mut response = Response::new();
+    response.status = status;
+    response.content_type = content_types::JSON;
+    response.body = ResponseBody::Owned(
+        serde_json::to_vec(&ErrorEnvelope {
+            error: ErrorBody { code, message },
+        })
+        .unwrap(),
+    );
+    response
+}
+
+/// The site's own `<status>.html` page, or a bare one for statuses it doesn't have a page for.
+pub fn page<'a>(status: u16) -> Response<'a> {
+    let mut response = Response::new();
+    response.status = status;
+    response.content_type = content_types::HTML;
+    response.body = match ERROR_PAGES.iter().find(|(page_status, _)| *page_status == status) {
+        Some((_, body)) => ResponseBody::Lifetime(body),
+        None => ResponseBody::Owned(
+            format!("<html><body><h1>{} {}</h1></body></html>", status, status_to_message(status)).into_bytes(),
+        ),
+    };
+    response
+}
+
+/// The response for an error with nothing more specific to say than its status. `request` is `None` when it
+/// couldn't be parsed, which always gets the HTML page.
+pub fn response<'a>(status: u16, request: Option<&Request>) -> Response<'a> {
+    match request {
+        Some(request) if wants_json(request) => {
+            let message = status_to_message(status);
+            json(status, &message.to_lowercase().replace(' ', "_"), &message)
+        }
+        _ => page(status),
+    }
+}
+
+/// The response to a request that never made it as far as routing, the same from either server.
+pub fn unparsed<'a>(reason: Unparsed) -> Response<'a> {
+    let status = match reason {
+        Unparsed::Malformed => 400,
+        Unparsed::Cut(_) => 408,
+        Unparsed::TooLarge => 413,
+        Unparsed::HeadersTooLarge => 431,
+    };
+    let mut response = page(status);
+    // Whatever else the client sent can't be told apart from the start of another request.
+    response.headers.push("Connection: close".to_string());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wants_json_for(path: &str, accept: Option<&str>) -> bool {
+        let accept = accept.map(|accept| format!("Accept: {}\r\n", accept)).unwrap_or_default();
+        wants_json(&Request::parse(format!("GET {} HTTP/1.1\r\n{}\r\n", path, accept).as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn api_errors_are_always_json() {
+        assert!(wants_json_for("/api/visits", None));
+        assert!(wants_json_for("/api/visits", Some("text/html")));
+        assert!(!wants_json_for("/missing", None));
+    }
+
+    #[test]
+    fn browsers_get_html() {
+        let firefox = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+        assert!(!wants_json_for("/missing", Some(firefox)));
+        assert!(!wants_json_for("/missing", Some("*/*")));
+        assert!(!wants_json_for("/missing", Some("text/*")));
+    }
+
+    #[test]
+    fn json_is_used_when_preferred() {
+        assert!(wants_json_for("/missing", Some("application/json")));
+        assert!(wants_json_for("/missing", Some("Application/JSON; charset=utf-8")));
+        assert!(wants_json_for("/missing", Some("application/*")));
+        assert!(wants_json_for("/missing", Some("text/html;q=0.5, application/json")));
+        assert!(wants_json_for("/missing", Some("application/json, */*")));
+    }
+
+    #[test]
+    fn qualities_are_respected() {
+        assert!(!wants_json_for("/missing", Some("application/json;q=0")));
+        assert!(!wants_json_for("/missing", Some("application/json;q=0.5, text/html")));
+        assert!(!wants_json_for("/missing", Some("application/json;q=nope")));
+        // The specific range decides over the wildcard, even when it is listed later.
+        assert!(!wants_json_for("/missing", Some("application/*, application/json;q=0")));
+        assert!(wants_json_for("/missing", Some("application/json;q=0.2, */*;q=0.1")));
+    }
+
+    #[test]
+    fn media_types_match_whole() {
+        assert!(!wants_json_for("/missing", Some("application/jsonp")));
+        assert!(!wants_json_for("/missing", Some("application/json-patch+json")));
+        assert!(!wants_json_for("/missing", Some("text/plain, x-application/json")));
+    }
+
+    #[test]
+    fn ties_go_to_html() {
+        assert!(!wants_json_for("/missing", Some("application/json, text/html")));
+        assert!(!wants_json_for("/missing", Some("application/*, text/*")));
+    }
+
+    #[test]
+    fn responses_follow_the_negotiation() {
+        let request = Request::parse(b"GET /missing HTTP/1.1\r\nAccept: application/json\r\n\r\n").unwrap();
+        let not_found = response(404, Some(&request));
+        assert_eq!(not_found.content_type, content_types::JSON);
+        assert_eq!(not_found.body.data(), br#"{"error":{"code":"not_found","message":"Not Found"}}"#);
+        assert_eq!(response(404, None).content_type, content_types::HTML);
+    }
+
+    #[test]
+    fn unparsed_requests_close_the_connection() {
+        assert_eq!(unparsed(Unparsed::Malformed).status, 400);
+        assert_eq!(unparsed(Unparsed::TooLarge).status, 413);
+        let response = unparsed(Unparsed::HeadersTooLarge);
+        assert_eq!(response.status, 431);
+        assert!(response.headers.contains(&"Connection: close".to_string()));
+    }
+}
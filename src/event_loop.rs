@@ -0,0 +1,484 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::access_log::{AccessEntry, AccessLog};
+use crate::config::{Config, TimeoutConfig};
+use crate::cors::Cors;
+use crate::database::Database;
+use crate::errors;
+use crate::handler::{
+    self, ACCEPT_BATCH_SIZE, Router, SHUTDOWN_POLL_INTERVAL, Server, UNPARSED_ROUTE,
+};
+use crate::http_utils::{Cut, Request, Response, TransferDeadline, Unparsed, MAX_REQUEST_SIZE};
+use crate::metrics::Metrics;
+use crate::middleware::Chain;
+use crate::panics;
+use crate::poll::{self, Interest};
+use crate::proxy::{self, IpCidr, ProxyProtocolMode};
+use crate::rate_limit::{ConnectionGuard, ConnectionLimit, RateLimiter};
+use crate::security_headers::SecurityHeaders;
+use crate::timing::{self, Phase, Timings};
+use crate::logger::{debug, error, info, warn};
+
+// Time spent pushing bytes around each frame before handing back to the main loop.
+const STEP_BUDGET: Duration = Duration::from_millis(8);
+const READ_CHUNK_SIZE: usize = 2048;
+
+enum State {
+    Reading {
+        data: Vec<u8>,
+    },
+    Writing {
+        head: Vec<u8>,
+        response: Box<Response<'static>>,
+        // Bytes of `head` followed by the body that have been written so far.
+        offset: usize,
+        route: String,
+        deadline: TransferDeadline,
+        // For the access log, missing when the request couldn't be parsed.
+        request: Option<Box<Request>>,
+    },
+    Closed,
+}
+
+struct Connection {
+    stream: TcpStream,
+    socket_address: SocketAddr,
+    accepted: Instant,
+    state: State,
+    // Counts against the client for as long as the connection is open.
+    guard: ConnectionGuard,
+    // Only kept for requests that made it as far as routing.
+    timings: Option<Timings>,
+    responded: Instant,
+}
+
+impl Connection {
+    /// What the connection is waiting on the socket for, `None` once it is done with it.
+    fn interest(&self) -> Option<Interest> {
+        match self.state {
+            State::Reading { .. } => Some(Interest::Read),
+            State::Writing { .. } => Some(Interest::Write),
+            State::Closed => None,
+        }
+    }
+}
+
+/// A single threaded server that keeps every socket nonblocking and drives each connection through a small
+/// state machine, reading the request then writing the response a bit at a time. Only the sockets poll(2)
+/// reports ready are touched, the rest just have their deadlines checked. Routing is shared with the threaded
+/// `Handler`.
+pub struct EventLoop {
+    server: TcpListener,
+    connections: Vec<Connection>,
+    chain: Chain,
+    metrics: Arc<Metrics>,
+    rate_limiter: RateLimiter,
+    connection_limit: ConnectionLimit,
+    trusted_proxies: Vec<IpCidr>,
+    proxy_protocol: ProxyProtocolMode,
+    max_connections: usize,
+    timeouts: TimeoutConfig,
+    access_log: AccessLog,
+    security_headers: Arc<SecurityHeaders>,
+    server_timing: bool,
+}
+
+impl EventLoop {
+    pub fn new(db: Arc<Mutex<Database>>, config: &Config) -> Self {
+        let rate_limiter = RateLimiter::new(&config.rate_limit, &config.server.trusted_proxies);
+        let connection_limit = rate_limiter.connection_limit();
+        let timeouts = config.timeouts;
+        let access_log = AccessLog::new(&config.access_log);
+        let security_headers = Arc::new(SecurityHeaders::new(&config.security_headers));
+        let cors = Cors::new(&config.cors);
+        let config = &config.server;
+        let server = TcpListener::bind(("0.0.0.0", config.port)).unwrap();
+        server.set_nonblocking(true).unwrap();
+        info!("Event loop serving up to {} connections", config.queue_max_size);
+        let metrics = Arc::new(Metrics::new(config.ready_queue_percent));
+
+        Self {
+            server,
+            connections: Vec::with_capacity(config.queue_max_size),
+            chain: handler::middleware_chain(Router::new(db, metrics.clone()), security_headers.clone(), cors),
+            metrics,
+            rate_limiter,
+            connection_limit,
+            trusted_proxies: config.trusted_proxies.clone(),
+            proxy_protocol: config.proxy_protocol,
+            max_connections: config.queue_max_size,
+            timeouts,
+            access_log,
+            security_headers,
+            server_timing: config.server_timing,
+        }
+    }
+
+    fn accept(&mut self) {
+        for _ in 0..ACCEPT_BATCH_SIZE {
+            match self.server.accept() {
+                Ok((stream, socket_address)) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        warn!("Error making stream nonblocking: {e}");
+                        continue;
+                    }
+                    // Only connections that get this far are tracked, so the list never outgrows the cap.
+                    let guard = match self.connection_limit.accept(socket_address.ip()) {
+                        Ok(_) if self.connections.len() >= self.max_connections => {
+                            self.turn_away(stream, errors::response(503, None));
+                            continue;
+                        }
+                        Ok(guard) => guard,
+                        Err(retry_after) => {
+                            let mut response = errors::response(429, None);
+                            response.headers.push(format!("Retry-After: {}", retry_after));
+                            self.turn_away(stream, response);
+                            continue;
+                        }
+                    };
+                    self.connections.push(Connection {
+                        stream,
+                        socket_address,
+                        accepted: Instant::now(),
+                        state: State::Reading { data: vec![] },
+                        guard,
+                        timings: None,
+                        responded: Instant::now(),
+                    });
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => return,
+                    _ => {
+                        error!("Error accepting connection: {e}");
+                        return;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Answers a connection there is no room for with a single write and closes it without tracking it. An
+    /// error page fits in a new socket's send buffer, anything that doesn't go straight away is dropped.
+    fn turn_away(&self, mut stream: TcpStream, mut response: Response<'static>) {
+        self.security_headers.apply(None, &mut response);
+        let mut data = response.head().into_bytes();
+        data.extend_from_slice(response.body.data());
+        let sent = stream.write(&data).unwrap_or_else(|e| {
+            debug!("Error turning away connection: {e}");
+            0
+        });
+        self.metrics.record_request(UNPARSED_ROUTE, response.status);
+        self.metrics.add_bytes_sent(response.encoding(), sent);
+        if let Err(e) = stream.shutdown(Shutdown::Both) {
+            debug!("Error shutting down stream: {e}");
+        }
+    }
+
+    fn respond(
+        &self,
+        connection: &mut Connection,
+        response: Response<'static>,
+        route: String,
+        request: Option<Request>,
+    ) {
+        connection.responded = Instant::now();
+        connection.state = State::Writing {
+            head: response.head().into_bytes(),
+            response: Box::new(response),
+            offset: 0,
+            route,
+            deadline: TransferDeadline::new(&self.timeouts),
+            request: request.map(Box::new),
+        };
+    }
+
+    /// Sends a response that didn't go through the middleware chain, the same as the threaded `server_error`.
+    fn respond_error(
+        &self,
+        connection: &mut Connection,
+        mut response: Response<'static>,
+        route: String,
+        request: Option<Request>,
+    ) {
+        self.security_headers.apply(None, &mut response);
+        self.respond(connection, response, route, request);
+    }
+
+    /// Turns a fully read request into a response, the same way the threaded reader and workers would.
+    fn handle(&mut self, connection: &mut Connection, data: &[u8]) {
+        let parse_started = Instant::now();
+        let (client_addr, preamble_len) = match proxy::accept_proxy_header(
+            self.proxy_protocol,
+            &connection.socket_address,
+            data,
+            &self.trusted_proxies,
+        ) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                debug!("Rejecting connection: {e}");
+                self.respond_error(connection, errors::response(400, None), UNPARSED_ROUTE.to_string(), None);
+                return;
+            }
+        };
+        let mut request = match Request::parse(&data[preamble_len..]) {
+            Some(request) => request,
+            None => {
+                self.respond_error(connection, errors::unparsed(Unparsed::Malformed), UNPARSED_ROUTE.to_string(), None);
+                return;
+            }
+        };
+        request.resolve_client_ip(&client_addr, &self.trusted_proxies);
+        let route = handler::route_label(&request).to_string();
+        let mut timings = Timings::default();
+        timings.set(Phase::Parse, parse_started.elapsed());
+
+        if let Err(retry_after) = self.rate_limiter.check(&request, &mut connection.guard) {
+            let mut response = errors::response(429, Some(&request));
+            response.headers.push(format!("Retry-After: {}", retry_after));
+            self.respond_error(connection, response, route, Some(request));
+            return;
+        }
+
+        let route_started = Instant::now();
+        timing::take_db_lock_wait();
+        let mut response = self.chain.handle(&request);
+        timings.set(Phase::Route, route_started.elapsed());
+        timings.set(Phase::DbLock, timing::take_db_lock_wait());
+        if self.server_timing {
+            response.headers.push(timings.header());
+        }
+        connection.timings = Some(timings);
+        self.respond(connection, response, route, Some(request));
+    }
+
+    fn read(&mut self, connection: &mut Connection) {
+        let State::Reading { data } = &mut connection.state else {
+            return;
+        };
+
+        let mut buffer = [0; READ_CHUNK_SIZE];
+        let limit = (MAX_REQUEST_SIZE - data.len()).min(READ_CHUNK_SIZE);
+        let finished = match connection.stream.read(&mut buffer[..limit]) {
+            // Hung up without sending anything, there is no one to answer.
+            Ok(0) if data.is_empty() => {
+                connection.state = State::Closed;
+                return;
+            }
+            // Done sending, whether or not the request is whole. Answered the same as the threaded reader would.
+            Ok(0) => true,
+            Ok(read) => {
+                data.extend_from_slice(&buffer[..read]);
+                Request::is_complete(data) || data.len() >= MAX_REQUEST_SIZE
+            }
+            // Poll can report a socket ready that then has nothing, the deadline still catches it.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => false,
+            Err(e) => {
+                debug!("Error reading from stream: {e}");
+                connection.state = State::Closed;
+                return;
+            }
+        };
+
+        if finished {
+            let data = std::mem::take(data);
+            if let Some(reason) = Request::over_limit(&data) {
+                self.respond_error(connection, errors::unparsed(reason), UNPARSED_ROUTE.to_string(), None);
+                return;
+            }
+            // Routing catches its own panics, this is for anything that goes wrong parsing.
+            if let Err(panic) = panics::catch(|| self.handle(connection, &data)) {
+                error!("Panic reading a request from {}: {}", connection.socket_address, panic);
+                self.metrics.record_panic("reader");
+                self.respond_error(connection, errors::response(500, None), UNPARSED_ROUTE.to_string(), None);
+            }
+        }
+    }
+
+    fn write(&mut self, connection: &mut Connection) {
+        let State::Writing {
+            head,
+            response,
+            offset,
+            route,
+            deadline,
+            request,
+            ..
+        } = &mut connection.state
+        else {
+            return;
+        };
+
+        let body = response.body.data();
+        let remaining = if *offset < head.len() {
+            &head[*offset..]
+        } else {
+            &body[*offset - head.len()..]
+        };
+        if remaining.is_empty() {
+            self.metrics.record_request(route, response.status);
+            self.metrics.add_bytes_sent(response.encoding(), *offset);
+            if let Some(request) = request {
+                self.access_log.log(&AccessEntry::new(request, response, *offset, connection.accepted));
+            }
+            if let Some(timings) = connection.timings.as_mut() {
+                timings.set(Phase::Send, connection.responded.elapsed());
+                self.metrics.record_timings(timings);
+            }
+            // Shutdown the stream (depending on the web browser used to view the page, this might cause some issues).
+            if let Err(e) = connection.stream.shutdown(Shutdown::Both) {
+                debug!("Error shutting down stream: {e}");
+            }
+            connection.state = State::Closed;
+            return;
+        }
+
+        match connection.stream.write(remaining) {
+            Ok(written) => {
+                *offset += written;
+                deadline.progress(written);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => {
+                debug!("Error writing to stream: {e}");
+                self.metrics.record_request(route, response.status);
+                self.metrics.add_bytes_sent(response.encoding(), *offset);
+                connection.state = State::Closed;
+            }
+        }
+    }
+
+    /// Answers a request that has run out of time to arrive with a 408, and cuts a response that has run out of
+    /// time to send.
+    fn check_deadline(&mut self, connection: &mut Connection) {
+        match &mut connection.state {
+            State::Reading { .. } => {
+                if connection.accepted.elapsed() > Duration::from_millis(self.timeouts.read_timeout_ms) {
+                    info!("Cut {}: {}", connection.socket_address, Cut::ReadTimeout.name());
+                    self.metrics.record_cut(Cut::ReadTimeout);
+                    let response = errors::unparsed(Unparsed::Cut(Cut::ReadTimeout));
+                    self.respond_error(connection, response, UNPARSED_ROUTE.to_string(), None);
+                }
+            }
+            State::Writing {
+                response,
+                offset,
+                route,
+                deadline,
+                ..
+            } => {
+                if let Err(cut) = deadline.check() {
+                    self.metrics.record_request(route, response.status);
+                    self.metrics.add_bytes_sent(response.encoding(), *offset);
+                    self.cut(connection, cut);
+                }
+            }
+            State::Closed => {}
+        }
+    }
+
+    fn cut(&self, connection: &mut Connection, cut: Cut) {
+        info!("Cut {}: {}", connection.socket_address, cut.name());
+        self.metrics.record_cut(cut);
+        let _ = connection.stream.shutdown(Shutdown::Both);
+        connection.state = State::Closed;
+    }
+
+    fn advance(&mut self, connection: &mut Connection) {
+        match connection.state {
+            State::Reading { .. } => self.read(connection),
+            State::Writing { .. } => self.write(connection),
+            State::Closed => {}
+        }
+    }
+
+    /// Waits up to `timeout` for any connection to be ready and gives each one that is a go, returning whether
+    /// any were.
+    fn advance_ready(&mut self, connections: &mut [Connection], timeout: Duration) -> bool {
+        let (open, sockets): (Vec<_>, Vec<_>) = connections
+            .iter()
+            .enumerate()
+            .filter_map(|(index, connection)| Some((index, (connection.stream.as_raw_fd(), connection.interest()?))))
+            .unzip();
+        if open.is_empty() {
+            return false;
+        }
+        let ready = match poll::ready(&sockets, timeout) {
+            Ok(ready) => ready,
+            Err(e) => {
+                error!("Error polling connections: {e}");
+                return false;
+            }
+        };
+
+        let mut any_ready = false;
+        for (index, ready) in open.into_iter().zip(ready) {
+            if ready {
+                self.advance(&mut connections[index]);
+                any_ready = true;
+            }
+        }
+        any_ready
+    }
+
+    fn check_deadlines(&mut self, connections: &mut [Connection]) {
+        for connection in connections.iter_mut() {
+            self.check_deadline(connection);
+        }
+    }
+}
+
+impl Server for EventLoop {
+    fn step(&mut self) {
+        self.accept();
+
+        let started = Instant::now();
+        let mut connections = std::mem::take(&mut self.connections);
+        while self.advance_ready(&mut connections, Duration::ZERO) && started.elapsed() < STEP_BUDGET {}
+        self.check_deadlines(&mut connections);
+        connections.retain(|connection| !matches!(connection.state, State::Closed));
+        self.connections = connections;
+
+        self.metrics
+            .set_queue_depth("connections", self.connections.len(), self.max_connections);
+        self.access_log.step();
+    }
+
+    fn shutdown(&mut self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut connections = std::mem::take(&mut self.connections);
+        // Requests still being read count as queued, responses already going out get to finish.
+        let mut turned_away = 0;
+        for connection in connections.iter_mut() {
+            if let State::Reading { .. } = connection.state {
+                self.respond_error(connection, handler::shutting_down(None), UNPARSED_ROUTE.to_string(), None);
+                turned_away += 1;
+            }
+        }
+        info!("Turning away {} queued requests", turned_away);
+
+        let mut last_pending = 0;
+        while !connections.is_empty() && Instant::now() < deadline {
+            self.advance_ready(&mut connections, SHUTDOWN_POLL_INTERVAL);
+            self.check_deadlines(&mut connections);
+            connections.retain(|connection| !matches!(connection.state, State::Closed));
+            if connections.len() != last_pending {
+                info!("Waiting on {} responses", connections.len());
+                last_pending = connections.len();
+            }
+        }
+
+        info!("Closing {} connections", connections.len());
+        for connection in connections {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+        self.access_log.flush();
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr().unwrap()
+    }
+}
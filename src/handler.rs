@@ -1,228 +1,750 @@
-use chrono::prelude::*;
 use core::net::SocketAddr;
-use std::collections::VecDeque;
 use std::net::{Shutdown, TcpListener, TcpStream};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::access_log::{AccessEntry, AccessLog};
 use crate::api;
+use crate::config::{Config, TimeoutConfig, WorkerPoolConfig};
+use crate::cors::Cors;
 use crate::database::Database;
-use crate::http_utils::{content_types, Request, Response, ResponseBody, SliceBody};
+use crate::errors;
+use crate::metrics::{self, Metrics, WorkerPool};
+use crate::middleware::Chain;
+use crate::panics;
+use crate::poll::{self, Interest};
+use crate::proxy::{self, IpCidr, ProxyProtocolMode};
+use crate::queue::BlockingQueue;
+use crate::scheduler::{JobClass, Scheduler};
+use crate::security_headers::SecurityHeaders;
+use crate::timing::{self, Phase, Timings};
+use crate::rate_limit::{ConnectionGuard, ConnectionLimit, RateLimiter};
+use crate::http_utils::{
+    parse_range, select_encoding, PendingRequest, RangeRequest, ReadState, Request, Response, ResponseBody,
+    SliceBody, Unparsed,
+};
+use crate::logger::{debug, error, info, warn};
 
 include!(concat!(env!("OUT_DIR"), "/dist.rs"));
 
 pub struct WorkJob {
     request: Request,
-    socket_address: SocketAddr,
     tcp_stream: TcpStream,
+    accepted: Instant,
+    queued: Instant,
+    timings: Timings,
+    // Held until the job is dropped so the client's open connection count stays accurate.
+    _connection: ConnectionGuard,
+}
+
+
+type JobScheduler = Arc<Scheduler<WorkJob>>;
+
+/// Marks a job finished when dropped, so a worker that panics part way through doesn't leave its class or the
+/// pool looking busy forever.
+struct JobGuard<'a> {
+    scheduler: &'a JobScheduler,
+    class: JobClass,
+    shared_busy: Option<&'a AtomicUsize>,
 }
 
+impl<'a> JobGuard<'a> {
+    fn new(scheduler: &'a JobScheduler, class: JobClass, shared_busy: Option<&'a AtomicUsize>) -> Self {
+        if let Some(shared_busy) = shared_busy {
+            shared_busy.fetch_add(1, Ordering::Relaxed);
+        }
+        Self {
+            scheduler,
+            class,
+            shared_busy,
+        }
+    }
+}
 
-type JobQueue = Arc<Mutex<VecDeque<WorkJob>>>;
+impl Drop for JobGuard<'_> {
+    fn drop(&mut self) {
+        self.scheduler.finish(self.class);
+        if let Some(shared_busy) = self.shared_busy {
+            shared_busy.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
 
 pub struct Worker {
     worker_id: usize,
-    db: Arc<Mutex<Database>>,
-    queue: JobQueue,
+    // Only take jobs of this class, `None` for shared workers.
+    reserved: Option<JobClass>,
+    chain: Arc<Chain>,
+    metrics: Arc<Metrics>,
+    scheduler: JobScheduler,
     keep_running: Arc<AtomicBool>,
+    timeouts: TimeoutConfig,
+    // Shared workers in the middle of a job, used to size the pool.
+    shared_busy: Arc<AtomicUsize>,
+    access_log: Arc<AccessLog>,
+    server_timing: bool,
 }
 
 impl Worker {
-    pub fn new(worker_id: usize, db: Arc<Mutex<Database>>, queue: JobQueue, keep_running: Arc<AtomicBool>) -> Self {
-        Self {worker_id,  db, queue, keep_running }
+    pub fn work(&mut self) {
+        let serves = self.reserved.map(|class| class.name()).unwrap_or("all");
+        debug!("Worker {} ({}) started on {}", self.worker_id, serves, std::thread::current().id().as_u64());
+        // Blocks until there is a job, the scheduler is only closed when the workers are being stopped.
+        while let Some((class, mut job)) = self.scheduler.pop(self.reserved) {
+            let started = Instant::now();
+            job.timings.set(Phase::Queue, started.duration_since(job.queued));
+            let shared_busy = self.reserved.is_none().then_some(self.shared_busy.as_ref());
+            let _guard = JobGuard::new(&self.scheduler, class, shared_busy);
+            timing::take_db_lock_wait();
+            let mut response = self.chain.handle(&job.request);
+            job.timings.set(Phase::Route, started.elapsed());
+            job.timings.set(Phase::DbLock, timing::take_db_lock_wait());
+            if self.server_timing {
+                response.headers.push(job.timings.header());
+            }
+
+            let sending = Instant::now();
+            let sent = response.send(&mut job.tcp_stream, self.keep_running.clone(), &self.timeouts);
+            job.timings.set(Phase::Send, sending.elapsed());
+            self.metrics.record_timings(&job.timings);
+            self.access_log.log(&AccessEntry::new(&job.request, &response, sent.bytes, job.accepted));
+            self.metrics.record_request(route_label(&job.request), response.status);
+            self.metrics.add_bytes_sent(response.encoding(), sent.bytes);
+            if let Some(cut) = sent.cut {
+                info!("Cut {} on {}: {}", job.request.client_ip(), job.request.path, cut.name());
+                self.metrics.record_cut(cut);
+            }
+            // Shutdown the stream (depending on the web browser used to view the page, this might cause some issues).
+            match job.tcp_stream.shutdown(Shutdown::Both) {
+                Ok(_) => {}
+                Err(e) => {
+                    debug!("Error shutting down stream: {e}");
+                }
+            }
+            self.metrics.add_worker_busy(self.worker_id, started.elapsed());
+        }
+
+        debug!("Worker {} running on {} stopped", self.worker_id, std::thread::current().id().as_u64());
     }
+}
 
-    fn get_job(&self) -> Option<WorkJob> {
-        let mut queue = self.queue.lock().unwrap();
-        queue.pop_front()
+pub struct AcceptedConnection {
+    tcp_stream: TcpStream,
+    socket_address: SocketAddr,
+    accepted: Instant,
+    // Counts against the client from the moment it is accepted, not only once its request has been read.
+    connection: ConnectionGuard,
+}
+
+type ConnectionQueue = Arc<BlockingQueue<AcceptedConnection>>;
+
+// How long the reader waits on the requests it is reading before checking for new connections.
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Reads and parses requests off accepted sockets so slow clients never hold up the main loop, then
+/// hands them to the worker queues. Sockets are read without blocking, so a client that is slow to send its
+/// request doesn't hold up the ones behind it.
+pub struct Reader {
+    connections: ConnectionQueue,
+    // Connections whose request is still arriving, no more than the connection queue holds.
+    pending: Vec<(AcceptedConnection, PendingRequest)>,
+    max_pending: usize,
+    scheduler: JobScheduler,
+    large_asset_bytes: usize,
+    metrics: Arc<Metrics>,
+    keep_running: Arc<AtomicBool>,
+    trusted_proxies: Vec<IpCidr>,
+    proxy_protocol: ProxyProtocolMode,
+    rate_limiter: RateLimiter,
+    timeouts: TimeoutConfig,
+    access_log: Arc<AccessLog>,
+    security_headers: Arc<SecurityHeaders>,
+}
+
+impl Reader {
+    pub fn read(&mut self) {
+        debug!("Reader started on {}", std::thread::current().id().as_u64());
+        loop {
+            // Only wait on the queue when there is nothing else to do.
+            if self.pending.is_empty() {
+                match self.connections.pop() {
+                    Some(connection) => self.start(connection),
+                    None => break,
+                }
+            }
+            while self.pending.len() < self.max_pending
+                && let Some(connection) = self.connections.try_pop()
+            {
+                self.start(connection);
+            }
+            if self.connections.is_closed() {
+                break;
+            }
+
+            self.read_pending();
+        }
+
+        // Requests still being read count as queued, and are turned away the same.
+        for (connection, _) in self.pending.drain(..) {
+            server_error(connection.tcp_stream, &shutting_down(None), self.keep_running.clone(), &self.metrics, UNPARSED_ROUTE, &self.timeouts, &self.security_headers);
+        }
+        debug!("Reader running on {} stopped", std::thread::current().id().as_u64());
     }
 
-    fn should_run(&self) -> bool {
-        self.keep_running.load(Ordering::Relaxed)
+    fn start(&mut self, connection: AcceptedConnection) {
+        if let Err(e) = connection.tcp_stream.set_nonblocking(true) {
+            warn!("Error making stream nonblocking: {e}");
+            return;
+        }
+        let request = PendingRequest::new(connection.accepted, &self.timeouts);
+        self.pending.push((connection, request));
     }
 
-    pub fn work(&mut self) {
-        println!("Worker {} started on {}", self.worker_id, std::thread::current().id().as_u64());
-        while self.should_run() {
-            let mut job = match self.get_job() {
-                Some(job) => job,
-                None => {
-                    std::thread::sleep(Duration::from_millis(50));
-                    continue;
-                }
+    /// Waits a little for any of the connections being read to send something, then reads from the ones that
+    /// did and hands on the requests that are complete.
+    fn read_pending(&mut self) {
+        let sockets: Vec<_> = self
+            .pending
+            .iter()
+            .map(|(connection, _)| (connection.tcp_stream.as_raw_fd(), Interest::Read))
+            .collect();
+        let ready = match poll::ready(&sockets, READ_POLL_INTERVAL) {
+            Ok(ready) => ready,
+            Err(e) => {
+                error!("Error polling connections: {e}");
+                std::thread::sleep(READ_POLL_INTERVAL);
+                vec![false; sockets.len()]
+            }
+        };
+
+        // Backwards, so removing a connection doesn't move any still to be looked at.
+        for index in (0..self.pending.len()).rev() {
+            let (connection, request) = &mut self.pending[index];
+            let state = if ready[index] {
+                request.read(&connection.tcp_stream)
+            } else {
+                request.check_deadline()
+            };
+            let read = match state {
+                ReadState::Idle | ReadState::Reading => continue,
+                ReadState::Done(data) => Ok(data),
+                ReadState::Failed(reason) => Err(reason),
             };
+            let (connection, _) = self.pending.swap_remove(index);
+            // The connection is dropped, closing it, as the panic unwinds.
+            if let Err(panic) = panics::catch(|| self.handle(connection, read)) {
+                error!("Panic reading a request: {}", panic);
+                self.metrics.record_panic("reader");
+            }
+        }
+    }
 
-            let response = route(&job.request, self.db.clone(), &job.socket_address);
-            println!(
-                "{} {} {} {} {}\n",
-                Utc::now().format("%Y-%m-%d %H:%M:%S"),
-                self.worker_id,
-                job.request.method,
-                job.request.path,
-                response.status
-            );
-
-            response.send(&mut job.tcp_stream, self.keep_running.clone());
-            // Shutdown the stream (depending on the web browser used to view the page, this might cause some issues).
-            match job.tcp_stream.shutdown(Shutdown::Both) {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("Error shutting down stream: {e}");
+    fn handle(&mut self, connection: AcceptedConnection, read: Result<Option<Vec<u8>>, Unparsed>) {
+        let AcceptedConnection {
+            tcp_stream: stream,
+            socket_address: socket_addr,
+            accepted,
+            mut connection,
+        } = connection;
+
+        let data = match read {
+            Ok(Some(data)) => data,
+            // Hung up without sending anything, there is no one to answer.
+            Ok(None) => {
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+            Err(reason) => {
+                if let Unparsed::Cut(cut) = reason {
+                    info!("Cut {}: {}", socket_addr, cut.name());
+                    self.metrics.record_cut(cut);
                 }
+                server_error(stream, &errors::unparsed(reason), self.keep_running.clone(), &self.metrics, UNPARSED_ROUTE, &self.timeouts, &self.security_headers);
+                return;
+            }
+        };
+        // Responses are sent from blocking sockets, with a write timeout.
+        if let Err(e) = stream.set_nonblocking(false) {
+            warn!("Error making stream blocking: {e}");
+        }
+        let parse_started = Instant::now();
+        let (client_addr, preamble_len) = match proxy::accept_proxy_header(
+            self.proxy_protocol,
+            &socket_addr,
+            &data,
+            &self.trusted_proxies,
+        ) {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                debug!("Rejecting connection: {e}");
+                let response = errors::response(400, None);
+                server_error(stream, &response, self.keep_running.clone(), &self.metrics, UNPARSED_ROUTE, &self.timeouts, &self.security_headers);
+                return;
+            }
+        };
+        let mut request = match Request::parse(&data[preamble_len..]) {
+            Some(request) => request,
+            None => {
+                server_error(stream, &errors::unparsed(Unparsed::Malformed), self.keep_running.clone(), &self.metrics, UNPARSED_ROUTE, &self.timeouts, &self.security_headers);
+                return;
             }
+        };
+        request.resolve_client_ip(&client_addr, &self.trusted_proxies);
+        let mut timings = Timings::default();
+        timings.set(Phase::Parse, parse_started.elapsed());
+
+        if let Err(retry_after) = self.rate_limiter.check(&request, &mut connection) {
+            let mut response = errors::response(429, Some(&request));
+            response.headers.push(format!("Retry-After: {}", retry_after));
+            let sent = server_error(stream, &response, self.keep_running.clone(), &self.metrics, route_label(&request), &self.timeouts, &self.security_headers);
+            self.access_log.log(&AccessEntry::new(&request, &response, sent, accepted));
+            return;
         }
 
-        println!("Worker {} running on {} stopped", self.worker_id, std::thread::current().id().as_u64());
+        let class = classify(&request, self.large_asset_bytes);
+        let job = WorkJob {
+            request,
+            tcp_stream: stream,
+            accepted,
+            queued: Instant::now(),
+            timings,
+            _connection: connection,
+        };
+        if let Err(job) = self.scheduler.push(class, job) {
+            let response = errors::response(503, Some(&job.request));
+            let sent = server_error(job.tcp_stream, &response, self.keep_running.clone(), &self.metrics, route_label(&job.request), &self.timeouts, &self.security_headers);
+            self.access_log.log(&AccessEntry::new(&job.request, &response, sent, job.accepted));
+        }
     }
 }
 
+// How many connections the main loop accepts per frame before getting back to input and the consoles.
+pub const ACCEPT_BATCH_SIZE: usize = 16;
+// How often shutdown checks whether the last responses have gone out.
+pub const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a thread the handler started is for, so one that dies can be replaced.
+#[derive(Clone, Copy)]
+enum Role {
+    Reader,
+    /// Reserved for a class, or `None` for a shared worker.
+    Worker(Option<JobClass>),
+}
+
+/// Something the main loop drives once per frame.
+pub trait Server {
+    fn step(&mut self);
+    /// Stops taking new requests, turns away anything still queued and gives the responses already being
+    /// sent until `timeout` to finish before cutting them off.
+    fn shutdown(&mut self, timeout: Duration);
+    /// Where the server is listening, which tells you the port when the config asked for any free one.
+    fn local_addr(&self) -> SocketAddr;
+}
+
 pub struct Handler {
     server: TcpListener,
-    queue: JobQueue,
-    index_queue: JobQueue,
-    worker_threads: Vec<JoinHandle<()>>,
+    connections: ConnectionQueue,
+    connection_limit: ConnectionLimit,
+    scheduler: JobScheduler,
+    worker_threads: Vec<(JoinHandle<()>, Role)>,
     keep_running: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+    timeouts: TimeoutConfig,
+    chain: Arc<Chain>,
+    access_log: Arc<AccessLog>,
+    security_headers: Arc<SecurityHeaders>,
+    server_timing: bool,
+    pool: WorkerPoolConfig,
+    shared_workers: usize,
+    shared_busy: Arc<AtomicUsize>,
+    next_worker_id: usize,
+    // Busy and running shared workers summed over every frame since the pool was last resized.
+    busy_samples: usize,
+    running_samples: usize,
+    last_scale: Instant,
 }
 
-const QUEUE_MAX_SIZE: usize = 100;
-
 impl Handler {
-    pub fn new(db: Arc<Mutex<Database>>, worker_count: usize) -> Self {
-        let server = TcpListener::bind("0.0.0.0:8081").unwrap();
+    pub fn new(db: Arc<Mutex<Database>>, config: &Config) -> Self {
+        let rate_limiter = RateLimiter::new(&config.rate_limit, &config.server.trusted_proxies);
+        let connection_limit = rate_limiter.connection_limit();
+        let timeouts = config.timeouts;
+        let pool = &config.workers;
+        let access_log_config = &config.access_log;
+        let security_headers = Arc::new(SecurityHeaders::new(&config.security_headers));
+        let cors = Cors::new(&config.cors);
+        let scheduler_config = &config.scheduler;
+        let config = &config.server;
+        let server = TcpListener::bind(("0.0.0.0", config.port)).unwrap();
         server.set_nonblocking(true).unwrap();
 
         let keep_running = Arc::new(AtomicBool::new(true));
-        let index_queue = JobQueue::default();
-        let queue = JobQueue::default();
-        
-        let mut worker = Worker::new(1, db.clone(), index_queue.clone(), keep_running.clone());
-        let thread = std::thread::Builder::new().spawn(move || {
-            worker.work();
+        let metrics = Arc::new(Metrics::new(config.ready_queue_percent));
+        let access_log = Arc::new(AccessLog::new(access_log_config));
+        let chain = Arc::new(middleware_chain(Router::new(db, metrics.clone()), security_headers.clone(), cors));
+        let connections = ConnectionQueue::new(BlockingQueue::new(config.queue_max_size));
+        let scheduler = JobScheduler::new(Scheduler::new(scheduler_config, config.queue_max_size));
+
+        let mut reader = Reader {
+            connections: connections.clone(),
+            pending: Vec::with_capacity(config.queue_max_size),
+            max_pending: config.queue_max_size,
+            scheduler: scheduler.clone(),
+            large_asset_bytes: scheduler_config.large_asset_bytes,
+            metrics: metrics.clone(),
+            keep_running: keep_running.clone(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            proxy_protocol: config.proxy_protocol,
+            rate_limiter,
+            timeouts,
+            access_log: access_log.clone(),
+            security_headers: security_headers.clone(),
+        };
+        let thread = std::thread::Builder::new().stack_size(pool.stack_size).spawn(move || {
+            reader.read();
         }).unwrap();
-        let mut worker_threads = vec![thread];
-        
-        for i in 0..worker_count {
-            let mut worker = Worker::new( i + 2, db.clone(), queue.clone(), keep_running.clone());
-            let thread = std::thread::Builder::new().spawn(move || {
-                worker.work();
-            }).unwrap();
-            worker_threads.push(thread);
-        }
 
-        Self {
+        let mut handler = Self {
             server,
-            queue,
-            index_queue,
-            worker_threads,
+            connections,
+            connection_limit,
+            scheduler,
+            worker_threads: vec![(thread, Role::Reader)],
             keep_running,
+            metrics,
+            timeouts,
+            chain,
+            access_log,
+            security_headers,
+            server_timing: config.server_timing,
+            pool: pool.clone(),
+            shared_workers: 0,
+            shared_busy: Arc::new(AtomicUsize::new(0)),
+            next_worker_id: 1,
+            busy_samples: 0,
+            running_samples: 0,
+            last_scale: Instant::now(),
+        };
+
+        // Reserved workers first, then the shared pool that serves every class.
+        for class in JobClass::ALL {
+            for _ in 0..scheduler_config.class(class).reserved_workers {
+                handler.spawn_worker(Some(class));
+            }
+        }
+        for _ in 0..pool.min {
+            handler.spawn_worker(None);
         }
+
+        handler
     }
 
-    pub fn stop_workers(&mut self) {
+    fn spawn_worker(&mut self, reserved: Option<JobClass>) {
+        let mut worker = Worker {
+            worker_id: self.next_worker_id,
+            reserved,
+            chain: self.chain.clone(),
+            metrics: self.metrics.clone(),
+            scheduler: self.scheduler.clone(),
+            keep_running: self.keep_running.clone(),
+            timeouts: self.timeouts,
+            shared_busy: self.shared_busy.clone(),
+            access_log: self.access_log.clone(),
+            server_timing: self.server_timing,
+        };
+        let thread = std::thread::Builder::new().stack_size(self.pool.stack_size).spawn(move || {
+            worker.work();
+        });
+        match thread {
+            Ok(thread) => {
+                self.worker_threads.push((thread, Role::Worker(reserved)));
+                self.next_worker_id += 1;
+                if reserved.is_none() {
+                    self.shared_workers += 1;
+                }
+            }
+            Err(e) => error!("Error starting worker: {e}"),
+        }
+    }
+
+    /// Adds a shared worker as soon as jobs that could run are waiting with none free, and drops one once the
+    /// pool has spent a whole interval mostly idle.
+    fn scale_workers(&mut self) {
+        let busy = self.shared_busy.load(Ordering::Relaxed);
+        self.busy_samples += busy;
+        self.running_samples += self.shared_workers;
+
+        let queued: usize = JobClass::ALL.iter().map(|class| self.scheduler.len(*class)).sum();
+        // Jobs held back by their class's limit would sit waiting however many workers there were.
+        let runnable = self.scheduler.runnable_len();
+        let interval = Duration::from_millis(self.pool.scale_interval_ms);
+        if self.last_scale.elapsed() >= interval {
+            let utilisation = self.busy_samples * 100 / self.running_samples.max(1);
+            if queued == 0 && utilisation < self.pool.scale_down_percent as usize && self.shared_workers > self.pool.min {
+                self.scheduler.retire_worker();
+                self.shared_workers -= 1;
+                info!("Stopping a worker, {} left ({}% busy)", self.shared_workers, utilisation);
+            }
+            self.busy_samples = 0;
+            self.running_samples = 0;
+            self.last_scale = Instant::now();
+        }
+        if runnable > 0 && busy >= self.shared_workers && self.shared_workers < self.pool.max {
+            self.spawn_worker(None);
+            info!("Starting a worker, {} running", self.shared_workers);
+        }
+
+        // Retired workers have already returned, so joining them here doesn't block.
+        let (finished, running): (Vec<_>, Vec<_>) =
+            self.worker_threads.drain(..).partition(|(thread, _)| thread.is_finished());
+        self.worker_threads = running;
+        for (thread, role) in finished {
+            // The panic itself was logged by the panic hook as it happened.
+            if thread.join().is_err() {
+                self.replace_thread(role);
+            }
+        }
+
+        self.metrics.set_worker_pool(WorkerPool {
+            current: self.shared_workers,
+            busy,
+            min: self.pool.min,
+            max: self.pool.max,
+        });
+    }
+
+    fn replace_thread(&mut self, role: Role) {
+        match role {
+            Role::Reader => {
+                // Panics while handling a connection are caught, so this means the queue itself is broken.
+                self.metrics.record_panic("reader");
+                error!("Reader stopped, no more requests will be read");
+            }
+            Role::Worker(reserved) => {
+                self.metrics.record_panic("worker");
+                if reserved.is_none() {
+                    self.shared_workers -= 1;
+                }
+                warn!("Worker died, starting another");
+                self.spawn_worker(reserved);
+            }
+        }
+    }
+
+    fn update_queue_metrics(&self) {
+        self.metrics.set_queue_depth("connections", self.connections.len(), self.connections.max_size());
+        for class in JobClass::ALL {
+            self.metrics.set_queue_depth(class.name(), self.scheduler.len(class), self.scheduler.max_size());
+        }
+    }
+}
+
+impl Server for Handler {
+    fn shutdown(&mut self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        // Closing the queues wakes the reader and workers, they finish what they have in hand and stop.
+        let connections = self.connections.close();
+        let jobs = self.scheduler.close();
+        info!("Turning away {} queued requests", connections.len() + jobs.len());
+        for connection in connections {
+            server_error(connection.tcp_stream, &shutting_down(None), self.keep_running.clone(), &self.metrics, UNPARSED_ROUTE, &self.timeouts, &self.security_headers);
+        }
+        for job in jobs {
+            let response = shutting_down(Some(&job.request));
+            let sent = server_error(job.tcp_stream, &response, self.keep_running.clone(), &self.metrics, route_label(&job.request), &self.timeouts, &self.security_headers);
+            self.access_log.log(&AccessEntry::new(&job.request, &response, sent, job.accepted));
+        }
+
+        let mut last_running = 0;
+        while Instant::now() < deadline {
+            let running = self.worker_threads.iter().filter(|(thread, _)| !thread.is_finished()).count();
+            if running == 0 {
+                break;
+            }
+            if running != last_running {
+                info!("Waiting on {} threads", running);
+                last_running = running;
+            }
+            std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+
+        // Anything still sending gives up at its next chunk.
         self.keep_running.store(false, Ordering::Relaxed);
-        println!("Sent kill signal to workers");
-        for thread in self.worker_threads.drain(..) {
-            thread.join().unwrap();
-            println!("Worker stopped");
+        for (thread, _) in self.worker_threads.drain(..) {
+            let _ = thread.join();
         }
+        info!("Workers stopped");
+        self.access_log.flush();
     }
 
-    pub fn step(&mut self) {
-        match self.server.accept() {
-            Ok((stream, socket_addr)) => {
-                // Queue full error out
-                let request = match Request::from(&stream) {
-                    Some(request) => request,
-                    None => {
-                        let response = &INTERNAL_SERVER_ERROR;
-                        server_error(stream, &response, self.keep_running.clone());
-                        return;
-                    }
-                };
-                
-
-                let queue = if request.path == "/" {
-                    if self.index_queue.lock().unwrap().len() < QUEUE_MAX_SIZE {
-                        &self.index_queue                        
-                    } else {
-                        &self.queue
+    fn local_addr(&self) -> SocketAddr {
+        self.server.local_addr().unwrap()
+    }
+
+    fn step(&mut self) {
+        self.update_queue_metrics();
+        self.scale_workers();
+        self.access_log.step();
+
+        for _ in 0..ACCEPT_BATCH_SIZE {
+            match self.server.accept() {
+                Ok((stream, socket_addr)) => {
+                    let connection = match self.connection_limit.accept(socket_addr.ip()) {
+                        Ok(connection) => connection,
+                        Err(retry_after) => {
+                            let mut response = errors::response(429, None);
+                            response.headers.push(format!("Retry-After: {}", retry_after));
+                            server_error(stream, &response, self.keep_running.clone(), &self.metrics, UNPARSED_ROUTE, &self.timeouts, &self.security_headers);
+                            continue;
+                        }
+                    };
+                    let connection = AcceptedConnection {
+                        tcp_stream: stream,
+                        socket_address: socket_addr,
+                        accepted: Instant::now(),
+                        connection,
+                    };
+                    if let Err(connection) = self.connections.push(connection) {
+                        let response = errors::response(503, None);
+                        server_error(connection.tcp_stream, &response, self.keep_running.clone(), &self.metrics, UNPARSED_ROUTE, &self.timeouts, &self.security_headers);
                     }
-                } else {
-                    &self.queue
-                };
-                let job = WorkJob {
-                    request,
-                    socket_address: socket_addr,
-                    tcp_stream: stream,
-                };
-                {
-                    let mut queue = queue.lock().unwrap();
-                    if queue.len() >= QUEUE_MAX_SIZE {
-                        let response = &SERVICE_UNAVAILABLE;
-                        server_error(job.tcp_stream, &response, self.keep_running.clone());
+                }
+                Err(e) => match e.kind() {
+                    // If the TCP socket would block execution, just try again.
+                    std::io::ErrorKind::WouldBlock => return,
+                    _ => {
+                        error!("Error accepting connection: {e}");
+                        std::thread::sleep(Duration::from_secs(2));
                         return;
                     }
-                    queue.push_back(job);
-                }
+                },
             }
-            Err(e) => match e.kind() {
-                // If the TCP socket would block execution, just try again.
-                std::io::ErrorKind::WouldBlock => {}
-                _ => {
-                    println!("Error accepting connection: {e}");
-                    std::thread::sleep(Duration::from_secs(2));
-                }
-            },
         }
     }
 }
 
-fn server_error(mut stream: TcpStream, response: &Response, keep_alive: Arc<AtomicBool>) {
-    response.send(&mut stream, keep_alive);
+/// Returns the number of bytes sent.
+fn server_error(mut stream: TcpStream, response: &Response, keep_alive: Arc<AtomicBool>, metrics: &Metrics, route: &str, timeouts: &TimeoutConfig, security_headers: &SecurityHeaders) -> usize {
+    let mut response = response.clone();
+    security_headers.apply(None, &mut response);
+    let sent = response.send(&mut stream, keep_alive, timeouts);
+    metrics.record_request(route, response.status);
+    metrics.add_bytes_sent(response.encoding(), sent.bytes);
+    if let Some(cut) = sent.cut {
+        metrics.record_cut(cut);
+    }
 
     // Shutdown the stream (depending on the web browser used to view the page, this might cause some issues).
     match stream.shutdown(Shutdown::Both) {
         Ok(_) => {}
         Err(e) => {
-            println!("Error shutting down stream: {e}");
+            debug!("Error shutting down stream: {e}");
+        }
+    }
+    sent.bytes
+}
+
+/// The 503 sent to anything still queued when the server stops.
+pub fn shutting_down<'a>(request: Option<&Request>) -> Response<'a> {
+    let mut response = errors::response(503, request);
+    response.headers.push("Connection: close".to_string());
+    response
+}
+
+// Label used for requests that could not be parsed far enough to have a path.
+pub const UNPARSED_ROUTE: &str = "unparsed";
+// Label for any path we don't serve, so scanners can't blow up the metric cardinality.
+const UNMATCHED_ROUTE: &str = "unmatched";
+const STATUS_ROUTES: [&str; 3] = ["/healthz", "/readyz", "/metrics"];
+
+pub fn route_label(request: &Request) -> &str {
+    let path = request.path.as_str();
+    if path == "/"
+        || api::ROUTES.contains(&path)
+        || STATUS_ROUTES.contains(&path)
+        || SERVE_REQUESTS.iter().any(|serve_request| serve_request.path == path)
+    {
+        path
+    } else {
+        UNMATCHED_ROUTE
+    }
+}
+
+fn classify(request: &Request, large_asset_bytes: usize) -> JobClass {
+    let path = request.path.as_str();
+    if path == "/" {
+        return JobClass::Html;
+    }
+    if path.starts_with("/api/") || STATUS_ROUTES.contains(&path) {
+        return JobClass::Api;
+    }
+
+    match SERVE_REQUESTS.iter().find(|serve_request| serve_request.path == path) {
+        Some(serve_request)
+            if serve_request.content_type.starts_with("video/")
+                || serve_request.content_type.starts_with("audio/")
+                || serve_request.body.len() >= large_asset_bytes =>
+        {
+            JobClass::Media
         }
+        Some(serve_request) if serve_request.content_type.starts_with("text/html") => JobClass::Html,
+        _ => JobClass::Asset,
     }
 }
 
-fn route<'a>(
-    request: &Request,
+/// The end of the middleware chain: the index page, status routes, the API, the rest of the built site and
+/// finally the 404 page.
+pub struct Router {
     db: Arc<Mutex<Database>>,
-    socket_address: &SocketAddr,
-) -> Response<'a> {
-    if request.method == "GET" && request.path == "/" {
-        return SERVE_REQUESTS[0].create_response(request);
+    metrics: Arc<Metrics>,
+}
+
+impl Router {
+    pub fn new(db: Arc<Mutex<Database>>, metrics: Arc<Metrics>) -> Self {
+        Self { db, metrics }
     }
 
-    if request.path.starts_with("/api/") {
-        if let Some(value) = api::route(request, db, socket_address) {
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn route<'a>(&self, request: &Request) -> Response<'a> {
+        if request.method == "GET" && request.path == "/" {
+            return SERVE_REQUESTS[0].create_response(request);
+        }
+
+        if STATUS_ROUTES.contains(&request.path.as_str())
+            && let Some(value) = metrics::route(request, self.db.clone(), &self.metrics)
+        {
             return value;
         }
-    }
 
-    for serve_request in SERVE_REQUESTS.iter() {
-        if serve_request.path == request.path {
-            if request.method == serve_request.method {
-                return serve_request.create_response(request);
-            }
-            if request.method == "HEAD" {
-                return serve_request.create_head_response(request);
+        if request.path.starts_with("/api/") {
+            return api::route(request, self.db.clone());
+        }
+
+        for serve_request in SERVE_REQUESTS.iter() {
+            if serve_request.path == request.path {
+                if request.method == serve_request.method {
+                    return serve_request.create_response(request);
+                }
+                if request.method == "HEAD" {
+                    return serve_request.create_head_response(request);
+                }
             }
         }
+
+        errors::response(404, Some(request))
     }
+}
 
-    let mut response = Response::new();
-    response.status = 404;
-    response.content_type = content_types::HTML;
-    response.body =
-        ResponseBody::Lifetime("<html><body><h1>404 Not Found</h1></body></html>".as_bytes());
-    response
+/// The middleware every request goes through on its way to `router`, outermost first. The security headers
+/// are shared so responses sent without routing can get them too.
+pub fn middleware_chain(router: Router, security_headers: Arc<SecurityHeaders>, cors: Cors) -> Chain {
+    Chain::new(router).with(security_headers).with(cors)
 }
 
 impl ServeRequest {
@@ -238,63 +760,34 @@ impl ServeRequest {
         response.content_type = self.content_type;
         response.headers.push("Accept-Ranges: bytes".to_string());
 
-        let accept_encoding = request
-            .get_header("Accept-Encoding")
-            .unwrap_or("".to_string());
-
-        let mut body = (self.body, "");
-        if self.body_gzip.is_some()
-            && accept_encoding.contains("gzip")
-            && self.body_gzip.unwrap().len() < body.0.len()
-        {
-            body = (self.body_gzip.unwrap(), "gzip");
-        }
-        if self.body_deflate.is_some()
-            && accept_encoding.contains("deflate")
-            && self.body_deflate.unwrap().len() < body.0.len()
-        {
-            body = (self.body_deflate.unwrap(), "deflate");
-        }
-        if self.body_br.is_some()
-            && accept_encoding.contains("br")
-            && self.body_br.unwrap().len() < body.0.len()
-        {
-            body = (self.body_br.unwrap(), "br");
-        }
-        if self.body_zstd.is_some()
-            && accept_encoding.contains("zstd")
-            && self.body_zstd.unwrap().len() < body.0.len()
-        {
-            body = (self.body_zstd.unwrap(), "zstd");
-        }
-        let (body, encoding) = body;
-
-        response.body = if let Some(range) = request.get_header("Range") {
-            let range = range.replace("bytes=", "");
-            let (start, end) = if range.ends_with('-') {
-                let start = range.trim_end_matches('-').parse::<usize>().unwrap_or(0);
-                let end = body.len();
-                (start, end)
-            } else if range.starts_with('-') {
-                let start = 0;
-                let end = body.len() - range.trim_start_matches('-').parse::<usize>().unwrap_or(body.len());
-                (start, end)
-            } else {
-                let mut parts = range.split('-');
-                let start = parts.next().unwrap().parse::<usize>().unwrap_or(0);
-                let end = parts.next().unwrap().parse::<usize>().unwrap_or(body.len());
-                (start, end)
-            };
-
-            response.status = 206;
-            response.headers.push(format!("Content-Range: bytes {}-{}/{}", start, end - 1, body.len()));
-            ResponseBody::Slice(SliceBody{
-                data: body,
-                start,
-                end,
-            })
-        } else {
-            ResponseBody::Lifetime(body)
+        let accept_encoding = request.get_header("Accept-Encoding").unwrap_or_default();
+        let (body, encoding) = select_encoding(
+            &accept_encoding,
+            self.body,
+            &[
+                ("gzip", self.body_gzip),
+                ("deflate", self.body_deflate),
+                ("br", self.body_br),
+                ("zstd", self.body_zstd),
+            ],
+        );
+
+        response.body = match request.get_header("Range").map(|range| parse_range(&range, body.len())) {
+            Some(RangeRequest::Satisfiable(range)) => {
+                response.status = 206;
+                response.headers.push(format!("Content-Range: bytes {}-{}/{}", range.start, range.end - 1, body.len()));
+                ResponseBody::Slice(SliceBody {
+                    data: body,
+                    start: range.start,
+                    end: range.end,
+                })
+            }
+            Some(RangeRequest::Unsatisfiable) => {
+                response.status = 416;
+                response.headers.push(format!("Content-Range: bytes */{}", body.len()));
+                ResponseBody::Empty
+            }
+            Some(RangeRequest::Ignored) | None => ResponseBody::Lifetime(body),
         };
         if !encoding.is_empty() {
             response
@@ -305,25 +798,3 @@ impl ServeRequest {
         return response;
     }
 }
-
-const INTERNAL_SERVER_ERROR: Response = Response {
-    version: 1.1,
-    status: 500,
-    content_type: content_types::HTML,
-    headers: vec![],
-    body: ResponseBody::Lifetime(
-        "<html><body><h1>500 Internal Server Error</h1></body></html>".as_bytes(),
-    ),
-    content_length_override: None,
-};
-
-const SERVICE_UNAVAILABLE: Response = Response {
-    version: 1.1,
-    status: 503,
-    content_type: content_types::HTML,
-    headers: vec![],
-    body: ResponseBody::Lifetime(
-        "<html><body><h1>503 Service Unavailable</h1></body></html>".as_bytes(),
-    ),
-    content_length_override: None,
-};
\ No newline at end of file
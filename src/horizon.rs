@@ -0,0 +1,73 @@
+//! The front end on the 3DS itself: the consoles on both screens and the buttons.
+
+use std::net::IpAddr;
+
+use ctru::prelude::*;
+use site_3ds::panics;
+use site_3ds::platform::{self, Input, Platform, Screen};
+
+struct Horizon<'gfx> {
+    gfx: &'gfx Gfx,
+    hid: Hid,
+    apt: Apt,
+    soc: Soc,
+    console: Option<Console<'gfx>>,
+}
+
+impl Platform for Horizon<'_> {
+    fn is_running(&mut self) -> bool {
+        self.apt.main_loop()
+    }
+
+    fn inputs(&mut self) -> Vec<Input> {
+        self.hid.scan_input();
+        let keys = self.hid.keys_down();
+        let mut inputs = vec![];
+        if keys.contains(KeyPad::START) {
+            inputs.push(Input::Exit);
+        }
+        if keys.contains(KeyPad::SELECT) {
+            inputs.push(Input::CycleLogLevel);
+        }
+        inputs
+    }
+
+    fn tick(&mut self) {
+        self.gfx.wait_for_vblank();
+    }
+
+    fn show(&mut self, screen: Screen) {
+        // The old console has to let go of its screen first, in case it is the one being asked for.
+        self.console = None;
+        self.console = Some(match screen {
+            Screen::Status => Console::new(self.gfx.bottom_screen.borrow_mut()),
+            Screen::Log => Console::new(self.gfx.top_screen.borrow_mut()),
+        });
+    }
+
+    fn host_address(&self) -> IpAddr {
+        IpAddr::V4(self.soc.host_address())
+    }
+
+    fn prompt(&self, input: Input) -> &'static str {
+        match input {
+            Input::CycleLogLevel => "Press Select",
+            Input::Exit => "Press Start",
+        }
+    }
+}
+
+pub fn main() {
+    ctru::applets::error::set_panic_hook(true);
+    panics::install_hook();
+
+    let gfx = Gfx::new().unwrap();
+    let mut horizon = Horizon {
+        gfx: &gfx,
+        hid: Hid::new().unwrap(),
+        apt: Apt::new().unwrap(),
+        soc: Soc::new().unwrap(),
+        console: None,
+    };
+    platform::run(&mut horizon);
+}
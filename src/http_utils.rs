@@ -1,28 +1,136 @@
 use std::{
     io::{self, Read, Write},
-    net::TcpStream, sync::{atomic::AtomicBool, Arc},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream}, ops::Range, sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant},
 };
 
-fn safe_send(stream: &mut TcpStream, data: &[u8]) -> Result<(), ()> {
+use crate::config::TimeoutConfig;
+use crate::proxy::{self, IpCidr};
+use crate::logger::{debug, warn};
+
+// Backoff between attempts on a socket that would block, doubling up to the max while nothing moves.
+const MIN_BACKOFF: Duration = Duration::from_millis(1);
+const MAX_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Why a connection was dropped for being too slow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cut {
+    /// The request didn't arrive within the read timeout.
+    ReadTimeout,
+    /// The client stopped accepting bytes for longer than the write timeout.
+    WriteTimeout,
+    /// The response was going out below the minimum transfer rate.
+    TooSlow,
+}
+
+impl Cut {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cut::ReadTimeout => "read_timeout",
+            Cut::WriteTimeout => "write_timeout",
+            Cut::TooSlow => "too_slow",
+        }
+    }
+}
+
+/// Why a request was answered without ever being routed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unparsed {
+    /// It arrived, but isn't a request we can make sense of.
+    Malformed,
+    /// It didn't all arrive in time.
+    Cut(Cut),
+    /// It reached `MAX_REQUEST_SIZE` before the end of its headers.
+    HeadersTooLarge,
+    /// It reached `MAX_REQUEST_SIZE` before the end of its body.
+    TooLarge,
+}
+
+/// Keeps track of how a response is going out against the write timeout and minimum transfer rate.
+pub struct TransferDeadline {
+    started: Instant,
+    last_progress: Instant,
+    transferred: usize,
+    write_timeout: Duration,
+    min_bytes_per_second: u64,
+    grace: Duration,
+}
+
+impl TransferDeadline {
+    pub fn new(timeouts: &TimeoutConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            last_progress: now,
+            transferred: 0,
+            write_timeout: Duration::from_millis(timeouts.write_timeout_ms),
+            min_bytes_per_second: timeouts.min_bytes_per_second,
+            grace: Duration::from_millis(timeouts.min_rate_grace_ms),
+        }
+    }
+
+    pub fn progress(&mut self, bytes: usize) {
+        if bytes > 0 {
+            self.transferred += bytes;
+            self.last_progress = Instant::now();
+        }
+    }
+
+    pub fn check(&self) -> Result<(), Cut> {
+        let now = Instant::now();
+        if now.duration_since(self.last_progress) > self.write_timeout {
+            return Err(Cut::WriteTimeout);
+        }
+        let elapsed = now.duration_since(self.started);
+        if self.min_bytes_per_second > 0 && elapsed > self.grace {
+            let rate = self.transferred as f64 / elapsed.as_secs_f64();
+            if rate < self.min_bytes_per_second as f64 {
+                return Err(Cut::TooSlow);
+            }
+        }
+        Ok(())
+    }
+
+    /// How long a blocking write may wait before the deadline needs checking again.
+    pub fn write_timeout(&self) -> Duration {
+        self.write_timeout
+    }
+}
+
+enum SendError {
+    Io,
+    Cut(Cut),
+}
+
+fn safe_send(stream: &mut TcpStream, data: &[u8], deadline: &mut TransferDeadline) -> Result<(), SendError> {
     let mut offset = 0;
-    loop {
-        let to_send = &data[offset..data.len()];
-        let write = match stream.write(&to_send) {
-            Ok(write) => write,
+    let mut backoff = MIN_BACKOFF;
+    while offset < data.len() {
+        deadline.check().map_err(SendError::Cut)?;
+        match stream.write(&data[offset..]) {
+            Ok(write) => {
+                offset += write;
+                deadline.progress(write);
+                backoff = MIN_BACKOFF;
+            }
+            // A blocking socket with a write timeout reports `TimedOut` on some platforms.
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
             Err(e) => {
-                if e.kind() == io::ErrorKind::WouldBlock {
-                    continue;
-                } else {
-                    println!("Error writing to stream: {e}");
-                    return Err(());
-                }
+                debug!("Error writing to stream: {e}");
+                return Err(SendError::Io);
             }
-        };
-        offset += write;
-        if offset >= data.len() {
-            return Ok(());
         }
     }
+    Ok(())
+}
+
+/// How much of a response made it out, and whether it was cut off for being too slow.
+pub struct Sent {
+    pub bytes: usize,
+    pub cut: Option<Cut>,
 }
 
 #[derive(Clone, Debug)]
@@ -50,8 +158,8 @@ impl ResponseBody<'_> {
         }
     }
 
-    pub fn chunks<'a>(&self, chunk_size: usize) -> std::slice::Chunks<'_, u8> {
-        let data = match self {
+    pub fn data(&self) -> &[u8] {
+        match self {
             ResponseBody::Lifetime(data) => {
                 &data[..]
             },
@@ -64,9 +172,11 @@ impl ResponseBody<'_> {
             ResponseBody::Empty => {
                 &EMPTY_BODY[..]
             },
-        };
+        }
+    }
 
-        data.chunks(chunk_size)
+    pub fn chunks<'a>(&self, chunk_size: usize) -> std::slice::Chunks<'_, u8> {
+        self.data().chunks(chunk_size)
     }
 }
 
@@ -92,16 +202,36 @@ impl<'a> Response<'a> {
         };
     }
 
-    pub fn send(&self, stream: &mut TcpStream, keep_alive: Arc<AtomicBool>) {
-        {
-            let mut send_body = String::with_capacity(256);
-            send_body.push_str(&format!(
-                "HTTP/{} {} {}\r\n",
-                self.version,
-                self.status,
-                status_to_message(self.status)
-            ));
-            send_body.push_str("Server: site-3ds\r\n");
+    pub fn has_header(&self, name: &str) -> bool {
+        self.headers.iter().any(|header| {
+            header
+                .split_once(':')
+                .is_some_and(|(header_name, _)| header_name.trim().eq_ignore_ascii_case(name))
+        })
+    }
+
+    /// The `Content-Encoding` the body is sent with, `identity` when it isn't compressed.
+    pub fn encoding(&self) -> &str {
+        for header in &self.headers {
+            if let Some(encoding) = header.strip_prefix("Content-Encoding: ") {
+                return encoding;
+            }
+        }
+        "identity"
+    }
+
+    /// The status line and headers, up to and including the blank line before the body.
+    pub fn head(&self) -> String {
+        let mut send_body = String::with_capacity(256);
+        send_body.push_str(&format!(
+            "HTTP/{} {} {}\r\n",
+            self.version,
+            self.status,
+            status_to_message(self.status)
+        ));
+        send_body.push_str("Server: site-3ds\r\n");
+        // A 204 has no body to describe.
+        if self.status != 204 {
             send_body.push_str(&format!("Content-Type: {}\r\n", self.content_type));
             send_body.push_str(&format!(
                 "Content-Length: {}\r\n",
@@ -111,29 +241,46 @@ impl<'a> Response<'a> {
                     self.body.len()
                 }
             ));
-            // send_body.push_str("Connection: close\r\n");
-            for header in &self.headers {
-                send_body.push_str(&format!("{}\r\n", header));
-            }
-            send_body.push_str("\r\n");
-            if safe_send(stream, send_body.as_bytes()).is_err() {
-                return;
-            }
+        }
+        // send_body.push_str("Connection: close\r\n");
+        for header in &self.headers {
+            send_body.push_str(&format!("{}\r\n", header));
+        }
+        send_body.push_str("\r\n");
+        send_body
+    }
+
+    /// Writes the response, giving up if the client falls foul of `timeouts`.
+    pub fn send(&self, stream: &mut TcpStream, keep_alive: Arc<AtomicBool>, timeouts: &TimeoutConfig) -> Sent {
+        let mut deadline = TransferDeadline::new(timeouts);
+        // Covers sockets that block, so a single write can't outlast the deadline.
+        if let Err(e) = stream.set_write_timeout(Some(deadline.write_timeout())) {
+            warn!("Error setting write timeout: {e}");
         }
 
-        for chunk in self.body.chunks(2000) {
+        let mut sent = Sent { bytes: 0, cut: None };
+        let send_body = self.head();
+        let chunks = std::iter::once(send_body.as_bytes()).chain(self.body.chunks(2000));
+        for chunk in chunks {
             if !keep_alive.load(std::sync::atomic::Ordering::Relaxed) {
-                return;
+                return sent;
             }
 
-            if safe_send(stream, chunk).is_err() {
-                return;
+            match safe_send(stream, chunk, &mut deadline) {
+                Ok(()) => sent.bytes += chunk.len(),
+                Err(SendError::Io) => return sent,
+                Err(SendError::Cut(cut)) => {
+                    sent.cut = Some(cut);
+                    return sent;
+                }
             }
         }
+
+        sent
     }
 }
 
-fn status_to_message(status: u16) -> String {
+pub fn status_to_message(status: u16) -> String {
     match status {
         200 => "OK".to_owned(),
         201 => "Created".to_owned(),
@@ -146,6 +293,11 @@ fn status_to_message(status: u16) -> String {
         403 => "Forbidden".to_owned(),
         404 => "Not Found".to_owned(),
         405 => "Method Not Allowed".to_owned(),
+        408 => "Request Timeout".to_owned(),
+        413 => "Content Too Large".to_owned(),
+        416 => "Range Not Satisfiable".to_owned(),
+        429 => "Too Many Requests".to_owned(),
+        431 => "Request Header Fields Too Large".to_owned(),
         500 => "Internal Server Error".to_owned(),
         503 => "Service Unavailable".to_owned(),
         _ => "Internal Server Error".to_owned(),
@@ -165,10 +317,89 @@ pub mod content_types {
     pub const ICON: &'static str = "image/vnd.microsoft.icon";
     #[allow(dead_code)]
     pub const JSON: &'static str = "application/json";
+    #[allow(dead_code)]
+    pub const PROMETHEUS: &'static str = "text/plain; version=0.0.4";
 }
 
 pub const EMPTY_BODY: &'static [u8] = &[];
 
+/// Requests (including any PROXY preamble) bigger than this are cut off.
+pub const MAX_REQUEST_SIZE: usize = 8192;
+
+/// How far reading a request off a nonblocking stream has got.
+pub enum ReadState {
+    /// Nothing arrived since the last read.
+    Idle,
+    /// More of the request arrived, but not all of it.
+    Reading,
+    /// The raw bytes of the request (and any PROXY protocol preamble in front of it), or `None` if the client
+    /// hung up without sending anything.
+    Done(Option<Vec<u8>>),
+    /// Cut off, or too big to be worth reading the rest of.
+    Failed(Unparsed),
+}
+
+/// A request arriving on a nonblocking stream, read a bit at a time so one thread can wait on many clients.
+pub struct PendingRequest {
+    data: Vec<u8>,
+    deadline: Instant,
+}
+
+impl PendingRequest {
+    pub fn new(accepted: Instant, timeouts: &TimeoutConfig) -> Self {
+        Self {
+            data: vec![],
+            deadline: accepted + Duration::from_millis(timeouts.read_timeout_ms),
+        }
+    }
+
+    /// Reads whatever has arrived without waiting for more.
+    pub fn read(&mut self, mut stream: &TcpStream) -> ReadState {
+        let mut buffer = [0; 2048];
+        let mut state = ReadState::Idle;
+        while self.data.len() < MAX_REQUEST_SIZE && !Request::is_complete(&self.data) {
+            let limit = (MAX_REQUEST_SIZE - self.data.len()).min(buffer.len());
+            match stream.read(&mut buffer[..limit]) {
+                Ok(0) => return self.finish(),
+                Ok(read) => {
+                    self.data.extend_from_slice(&buffer[..read]);
+                    state = ReadState::Reading;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return match state {
+                        ReadState::Idle => self.check_deadline(),
+                        state => state,
+                    };
+                }
+                Err(e) => {
+                    debug!("Error reading from stream: {e}");
+                    return self.finish();
+                }
+            }
+        }
+        self.finish()
+    }
+
+    /// Cuts the request off once it has taken longer than the read timeout, for when nothing has arrived to read.
+    pub fn check_deadline(&self) -> ReadState {
+        if Instant::now() >= self.deadline {
+            ReadState::Failed(Unparsed::Cut(Cut::ReadTimeout))
+        } else {
+            ReadState::Idle
+        }
+    }
+
+    fn finish(&mut self) -> ReadState {
+        if let Some(reason) = Request::over_limit(&self.data) {
+            ReadState::Failed(reason)
+        } else if self.data.is_empty() {
+            ReadState::Done(None)
+        } else {
+            ReadState::Done(Some(std::mem::take(&mut self.data)))
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Request {
     pub method: String,
@@ -179,84 +410,369 @@ pub struct Request {
     pub headers: Vec<String>,
     #[allow(dead_code)]
     pub body: String,
+    client_ip: IpAddr,
 }
 
 impl Request {
-    pub fn from(mut stream: &TcpStream) -> Option<Request> {
-        let mut buffer = [0; 8192];
-        let mut read_size = 0;
-        for _ in 0..10 {
-            match stream.read(&mut buffer) {
-                Ok(read) => {
-                    if read == 0 {
-                        break;
-                    }
-                    read_size += read;
-                }
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        std::thread::sleep(std::time::Duration::from_millis(10));
-                    }
-                }
-            }
-        }
+    /// Whether `data` holds the full head of a request plus as much body as its `Content-Length` promises.
+    pub fn is_complete(data: &[u8]) -> bool {
+        // The PROXY v2 signature starts with a blank line, so look for the end of the head after it.
+        let start = match proxy::parse_proxy_header(data) {
+            Ok(header) => header.map(|header| header.len).unwrap_or(0),
+            Err(_) => return false,
+        };
+        let data = &data[start..];
+        let head_end = match data.windows(4).position(|window| window == b"\r\n\r\n") {
+            Some(position) => position + 4,
+            None => return false,
+        };
+
+        let head = String::from_utf8_lossy(&data[..head_end]);
+        let content_length = head
+            .split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Length"))
+            .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+            .unwrap_or(0);
 
-        if read_size == 0 {
+        data.len() >= head_end.saturating_add(content_length)
+    }
+
+    /// Why `data` can't be served if it has filled `MAX_REQUEST_SIZE` without being a whole request, which is
+    /// refused rather than served cut short.
+    pub fn over_limit(data: &[u8]) -> Option<Unparsed> {
+        if data.len() < MAX_REQUEST_SIZE || Request::is_complete(data) {
             return None;
         }
+        let start = proxy::parse_proxy_header(data).ok().flatten().map(|header| header.len).unwrap_or(0);
+        let has_head = data[start..].windows(4).any(|window| window == b"\r\n\r\n");
+        Some(if has_head { Unparsed::TooLarge } else { Unparsed::HeadersTooLarge })
+    }
 
-        let mut buffer_v = Vec::from_iter(buffer.iter().copied());
-
-        buffer_v.pop();
-        let raw_req = match std::str::from_utf8(&buffer_v) {
-            Ok(raw_req) => raw_req.to_string(),
-            Err(e) => {
-                println!("Error parsing request: {e}");
+    /// Parses a request line, headers and body. Returns `None` for anything malformed, which is answered with
+    /// an error rather than guessed at.
+    pub fn parse(data: &[u8]) -> Option<Request> {
+        let (head, body) = match data.windows(4).position(|window| window == b"\r\n\r\n") {
+            Some(position) => (&data[..position], &data[position + 4..]),
+            None => (data, &[][..]),
+        };
+        let (head, body) = match (std::str::from_utf8(head), std::str::from_utf8(body)) {
+            (Ok(head), Ok(body)) => (head, body),
+            (Err(e), _) | (_, Err(e)) => {
+                debug!("Error parsing request: {e}");
                 return None;
             }
         };
-        let req_handler: Vec<&str> = raw_req.split("\r\n").collect();
-        let mut req: Vec<String> = vec![];
 
-        for i in &req_handler {
-            req.push(i.to_string())
+        let mut lines = head.split("\r\n");
+        let (method, path, version) = Self::parse_request_line(lines.next()?)?;
+        let headers = lines
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect();
+
+        Some(Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            version,
+            // Older clients padded the body out with NULs.
+            body: body.split('\0').next().unwrap_or_default().to_string(),
+            headers,
+            // Filled in by `resolve_client_ip` once we know who is on the other end of the socket.
+            client_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        })
+    }
+
+    /// `GET /path HTTP/1.1` into its method, path and version.
+    fn parse_request_line(line: &str) -> Option<(&str, &str, f32)> {
+        let mut parts = line.split(' ');
+        let method = parts.next().filter(|method| !method.is_empty())?;
+        let path = parts.next().filter(|path| !path.is_empty())?;
+        let version = parts.next()?.strip_prefix("HTTP/")?.parse::<f32>().ok()?;
+        if parts.next().is_some() || !version.is_finite() {
+            return None;
         }
+        Some((method, path, version))
+    }
 
-        let line_one: Vec<&str> = req[0].split(' ').collect();
+    pub fn get_header(&self, header: &str) -> Option<String> {
+        self.get_headers(header).first().map(|value| value.to_string())
+    }
 
-        let method = line_one[0].to_string();
-        let path = line_one[1].to_string();
+    /// Every value of a header that may be sent more than once, in the order received.
+    pub fn get_headers(&self, header: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.eq_ignore_ascii_case(header))
+            .map(|(_, value)| value.trim())
+            .collect()
+    }
 
-        let mut trash: Vec<&str> = line_one[2].split('/').collect(); //useless
-        let version = trash[1].parse::<f32>().unwrap();
+    /// Works out who the request is really from using `Forwarded` or `X-Forwarded-For`, but only when
+    /// `peer` (and every hop after it) is one of `trusted_proxies`.
+    pub fn resolve_client_ip(&mut self, peer: &SocketAddr, trusted_proxies: &[IpCidr]) {
+        let forwarded = self.get_headers("Forwarded");
+        let chain = if !forwarded.is_empty() {
+            forwarded.iter().flat_map(|value| proxy::forwarded_for(value)).collect::<Vec<_>>()
+        } else {
+            self.get_headers("X-Forwarded-For")
+                .iter()
+                .flat_map(|value| proxy::x_forwarded_for(value))
+                .collect::<Vec<_>>()
+        };
 
-        trash = req[req.len() - 1].split('\0').collect();
-        let body = trash[0].to_string();
+        self.client_ip = proxy::resolve_client_ip(peer.ip(), &chain, trusted_proxies);
+    }
 
-        req.remove(0);
-        req.remove(req.len() - 1);
-        req.remove(req.len() - 1);
+    pub fn client_ip(&self) -> IpAddr {
+        self.client_ip
+    }
+}
 
-        let headers: Vec<String> = req.to_vec(); // minus 2 'cause the last is the body and penultimate is "\r\n\r\n"
+/// What to do about a `Range` header.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// Not a single byte range we understand, so the whole body is sent as if there was no header.
+    Ignored,
+    /// The bytes to send, end exclusive.
+    Satisfiable(Range<usize>),
+    /// Asks for bytes past the end of the body.
+    Unsatisfiable,
+}
 
-        Some(Request {
-            method,
-            path,
-            version,
-            body,
-            headers,
-        })
+/// A plain decimal number, `str::parse` on its own also takes a leading `+`.
+fn parse_digits(value: &str) -> Option<usize> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
     }
+    value.parse().ok()
+}
 
-    pub fn get_header(&self, header: &str) -> Option<String> {
-        for h in &self.headers {
-            let mut splitter = h.splitn(2, ": ");
-            if let (Some(first), Some(second)) = (splitter.next(), splitter.next()) {
-                if first == header {
-                    return Some(second.to_string());
+/// Which bytes of a `len` byte body a `Range` header asks for. Only single ranges are supported, and the end
+/// in the header is inclusive, so `bytes=100-199` is 100 bytes.
+pub fn parse_range(header: &str, len: usize) -> RangeRequest {
+    let Some((first, last)) = header.trim().strip_prefix("bytes=").and_then(|spec| spec.split_once('-')) else {
+        return RangeRequest::Ignored;
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    match (parse_digits(first), parse_digits(last)) {
+        // `bytes=-500` is the last 500 bytes.
+        (None, Some(suffix)) if first.is_empty() => {
+            if suffix == 0 || len == 0 {
+                RangeRequest::Unsatisfiable
+            } else {
+                RangeRequest::Satisfiable(len.saturating_sub(suffix)..len)
+            }
+        }
+        // `bytes=500-` is everything from byte 500 on.
+        (Some(start), None) if last.is_empty() => {
+            if start >= len {
+                RangeRequest::Unsatisfiable
+            } else {
+                RangeRequest::Satisfiable(start..len)
+            }
+        }
+        (Some(start), Some(end)) if start <= end => {
+            if start >= len {
+                RangeRequest::Unsatisfiable
+            } else {
+                RangeRequest::Satisfiable(start..end.min(len - 1) + 1)
+            }
+        }
+        _ => RangeRequest::Ignored,
+    }
+}
+
+/// Whether an `Accept-Encoding` header allows `coding`, by name or through `*`, with a quality above zero.
+pub fn accepts_encoding(accept_encoding: &str, coding: &str) -> bool {
+    let mut wildcard = false;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .map_or(Some(1.0), |(_, value)| value.trim().parse::<f32>().ok());
+        let allowed = quality.is_some_and(|quality| quality > 0.0);
+        if name.eq_ignore_ascii_case(coding) {
+            return allowed;
+        }
+        if name == "*" {
+            wildcard = allowed;
+        }
+    }
+    wildcard
+}
+
+/// The smallest of the `encoded` bodies the client accepts along with its coding, or `identity` and an empty
+/// coding when none of them is any smaller.
+pub fn select_encoding<'a>(
+    accept_encoding: &str,
+    identity: &'a [u8],
+    encoded: &[(&'static str, Option<&'a [u8]>)],
+) -> (&'a [u8], &'static str) {
+    let mut selected = (identity, "");
+    for (coding, body) in encoded {
+        if let Some(body) = body
+            && body.len() < selected.0.len()
+            && accepts_encoding(accept_encoding, coding)
+        {
+            selected = (body, coding);
+        }
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn request(headers: &[&str]) -> Request {
+        let mut data = String::from("GET / HTTP/1.1\r\n");
+        for header in headers {
+            data.push_str(header);
+            data.push_str("\r\n");
+        }
+        data.push_str("\r\n");
+        Request::parse(data.as_bytes()).unwrap()
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let _ = Request::is_complete(&data);
+            let _ = Request::parse(&data);
+        }
+
+        #[test]
+        fn parse_round_trips(
+            method in "[A-Z]{1,8}",
+            path in "/[a-zA-Z0-9_./?=&-]{0,40}",
+            headers in proptest::collection::vec(("[A-Za-z-]{1,20}", "[ -~]{0,40}"), 0..8),
+            body in "[ -~]{0,64}",
+        ) {
+            let mut data = format!("{} {} HTTP/1.1\r\n", method, path);
+            for (name, value) in &headers {
+                data.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            data.push_str("\r\n");
+            data.push_str(&body);
+
+            let request = Request::parse(data.as_bytes()).unwrap();
+            prop_assert_eq!(request.method, method);
+            prop_assert_eq!(request.path, path);
+            prop_assert_eq!(request.version, 1.1);
+            prop_assert_eq!(request.headers.len(), headers.len());
+            prop_assert_eq!(request.body, body);
+        }
+
+        #[test]
+        fn header_lookup_ignores_case_and_whitespace(name in "[A-Za-z-]{1,20}", value in "[!-~]([ -~]{0,30}[!-~])?") {
+            let request = request(&[&format!("{}:  {} ", name.to_lowercase(), value)]);
+            prop_assert_eq!(request.get_header(&name.to_uppercase()), Some(value));
+        }
+
+        #[test]
+        fn range_is_within_body(header in "bytes=[0-9]{0,6}-[0-9]{0,6}", len in 0usize..100_000) {
+            if let RangeRequest::Satisfiable(range) = parse_range(&header, len) {
+                prop_assert!(range.start < range.end);
+                prop_assert!(range.end <= len);
+            }
+        }
+
+        #[test]
+        fn range_end_is_inclusive(start in 0usize..1000, count in 1usize..1000, len in 2000usize..5000) {
+            let header = format!("bytes={}-{}", start, start + count - 1);
+            prop_assert_eq!(parse_range(&header, len), RangeRequest::Satisfiable(start..start + count));
+        }
+
+        #[test]
+        fn range_never_panics(header in "\\PC{0,40}", len in any::<usize>()) {
+            let _ = parse_range(&header, len);
+        }
+
+        #[test]
+        fn selected_encoding_is_accepted_and_smallest(
+            accept in "\\PC{0,60}",
+            identity_len in 0usize..100,
+            gzip_len in proptest::option::of(0usize..100),
+            br_len in proptest::option::of(0usize..100),
+        ) {
+            let identity = vec![0; identity_len];
+            let gzip = gzip_len.map(|len| vec![0; len]);
+            let br = br_len.map(|len| vec![0; len]);
+            let encoded = [("gzip", gzip.as_deref()), ("br", br.as_deref())];
+
+            let (body, coding) = select_encoding(&accept, &identity, &encoded);
+            prop_assert!(body.len() <= identity.len());
+            if coding.is_empty() {
+                prop_assert_eq!(body.len(), identity.len());
+            } else {
+                prop_assert!(accepts_encoding(&accept, coding));
+            }
+            for (other, other_body) in encoded {
+                if let Some(other_body) = other_body && accepts_encoding(&accept, other) {
+                    prop_assert!(body.len() <= other_body.len());
                 }
             }
         }
-        None
+    }
+
+    #[test]
+    fn parse_rejects_malformed_request_lines() {
+        for data in ["", "GET", "GET /", "GET / HTTP/x", "GET  HTTP/1.1", "GET / HTTP/1.1 extra", "GET / HTTP/inf"] {
+            assert!(Request::parse(data.as_bytes()).is_none(), "{:?}", data);
+        }
+        assert!(Request::parse(b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn only_unfinished_requests_over_the_limit_are_refused() {
+        let mut headers = b"GET / HTTP/1.1\r\n".to_vec();
+        headers.resize(MAX_REQUEST_SIZE, b'a');
+        assert_eq!(Request::over_limit(&headers), Some(Unparsed::HeadersTooLarge));
+        assert_eq!(Request::over_limit(&headers[..MAX_REQUEST_SIZE - 1]), None);
+
+        let head = b"POST / HTTP/1.1\r\nContent-Length: 10000\r\n\r\n";
+        let mut body = head.to_vec();
+        body.resize(MAX_REQUEST_SIZE, b' ');
+        assert_eq!(Request::over_limit(&body), Some(Unparsed::TooLarge));
+
+        // Exactly filling the limit is fine.
+        let length = MAX_REQUEST_SIZE - head.len() + "10000".len() - 4;
+        let mut whole = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", length).into_bytes();
+        whole.resize(MAX_REQUEST_SIZE, b' ');
+        assert!(Request::is_complete(&whole));
+        assert_eq!(Request::over_limit(&whole), None);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=100-199", 1000), RangeRequest::Satisfiable(100..200));
+        assert_eq!(parse_range("bytes=900-", 1000), RangeRequest::Satisfiable(900..1000));
+        assert_eq!(parse_range("bytes=-100", 1000), RangeRequest::Satisfiable(900..1000));
+        assert_eq!(parse_range("bytes=-2000", 1000), RangeRequest::Satisfiable(0..1000));
+        assert_eq!(parse_range("bytes=500-5000", 1000), RangeRequest::Satisfiable(500..1000));
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=200-100", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("bytes=+1-2", 1000), RangeRequest::Ignored);
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Ignored);
+    }
+
+    #[test]
+    fn encodings() {
+        assert!(accepts_encoding("gzip, br", "br"));
+        assert!(accepts_encoding("GZIP;q=0.5", "gzip"));
+        assert!(!accepts_encoding("gzip;q=0", "gzip"));
+        assert!(accepts_encoding("*", "zstd"));
+        assert!(!accepts_encoding("*, zstd;q=0", "zstd"));
+        assert!(!accepts_encoding("gzip;q=nope", "gzip"));
+        assert!(!accepts_encoding("", "gzip"));
     }
 }
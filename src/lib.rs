@@ -0,0 +1,23 @@
+#![feature(thread_id_value)]
+//! The web server itself. `main.rs` picks the front end that drives it, through `platform::Platform`.
+mod access_log;
+pub mod api;
+pub mod config;
+mod cors;
+pub mod database;
+mod errors;
+pub mod event_loop;
+pub mod handler;
+pub mod http_utils;
+pub mod logger;
+pub mod metrics;
+mod middleware;
+pub mod panics;
+pub mod platform;
+mod poll;
+pub mod proxy;
+mod queue;
+mod rate_limit;
+mod scheduler;
+mod security_headers;
+mod timing;
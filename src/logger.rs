@@ -0,0 +1,337 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Arguments,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    sync::{
+        Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU8, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::config::LoggingConfig;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    /// Per request detail, too much for the screens in normal use.
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+    fn from_u8(value: u8) -> Level {
+        Level::ALL[(value as usize).min(Level::ALL.len() - 1)]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+// Everything in this crate logs under `site_3ds::`, which is just noise on a 50 column screen.
+const CRATE_PREFIX: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+const FILE_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+struct FileSink {
+    filename: String,
+    max_bytes: u64,
+    writer: BufWriter<File>,
+    size: u64,
+    last_flush: Instant,
+}
+
+impl FileSink {
+    fn open(filename: &str, max_bytes: u64) -> Option<FileSink> {
+        match OpenOptions::new().create(true).append(true).open(filename) {
+            Ok(file) => Some(FileSink {
+                filename: filename.to_string(),
+                max_bytes,
+                size: file.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+                writer: BufWriter::new(file),
+                last_flush: Instant::now(),
+            }),
+            Err(e) => {
+                println!("Error opening {}: {e}", filename);
+                None
+            }
+        }
+    }
+
+    /// Keeps a single previous file around as `<filename>.1`.
+    fn rotate(&mut self) {
+        let _ = self.writer.flush();
+        let _ = fs::rename(&self.filename, format!("{}.1", self.filename));
+        if let Some(sink) = FileSink::open(&self.filename, self.max_bytes) {
+            *self = sink;
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate();
+        }
+        if self.writer.write_all(line.as_bytes()).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+}
+
+/// Which levels are logged for which targets.
+struct Filter {
+    level: AtomicU8,
+    // The most verbose level anything is enabled at, so most disabled calls are turned away without a lock.
+    max_level: AtomicU8,
+    targets: RwLock<Vec<(String, Level)>>,
+}
+
+impl Filter {
+    const fn new(level: Level) -> Filter {
+        Filter {
+            level: AtomicU8::new(level as u8),
+            max_level: AtomicU8::new(level as u8),
+            targets: RwLock::new(Vec::new()),
+        }
+    }
+
+    fn set_targets(&self, targets: &BTreeMap<String, Level>) {
+        // Longest first so the most specific target wins.
+        let mut targets: Vec<(String, Level)> = targets
+            .iter()
+            .map(|(target, level)| (target.trim_start_matches(CRATE_PREFIX).to_string(), *level))
+            .collect();
+        targets.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        *self.targets.write().unwrap() = targets;
+        self.set_level(self.level());
+    }
+
+    fn level(&self) -> Level {
+        Level::from_u8(self.level.load(Ordering::Relaxed))
+    }
+
+    fn set_level(&self, level: Level) {
+        self.level.store(level as u8, Ordering::Relaxed);
+        let max_target = self.targets.read().unwrap().iter().map(|(_, level)| *level).max();
+        let max_level = max_target.map_or(level, |max_target| max_target.max(level));
+        self.max_level.store(max_level as u8, Ordering::Relaxed);
+    }
+
+    fn cycle_level(&self) -> Level {
+        let next = Level::from_u8((self.level() as u8 + 1) % Level::ALL.len() as u8);
+        self.set_level(next);
+        next
+    }
+
+    fn enabled(&self, level: Level, target: &str) -> bool {
+        if level as u8 > self.max_level.load(Ordering::Relaxed) {
+            return false;
+        }
+        let targets = self.targets.read().unwrap();
+        let target_level = targets
+            .iter()
+            .find(|(module, _)| is_within(target, module))
+            .map(|(_, level)| *level);
+        level <= target_level.unwrap_or_else(|| self.level())
+    }
+}
+
+/// Whether `target` is `module` or one of the modules under it, so `handler` covers `handler::reader` but not
+/// `handler_pool`.
+fn is_within(target: &str, module: &str) -> bool {
+    target
+        .strip_prefix(module)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+// Until `init` runs (while the config itself is being loaded) everything at info and up goes to the console.
+static FILTER: Filter = Filter::new(Level::Info);
+static CONSOLE: AtomicBool = AtomicBool::new(true);
+static FILE: Mutex<Option<FileSink>> = Mutex::new(None);
+
+pub fn init(config: &LoggingConfig) {
+    FILTER.set_targets(&config.targets);
+    CONSOLE.store(config.console, Ordering::Relaxed);
+    *FILE.lock().unwrap() = if config.file {
+        FileSink::open(&config.filename, config.max_bytes)
+    } else {
+        None
+    };
+    set_level(config.level);
+}
+
+pub fn level() -> Level {
+    FILTER.level()
+}
+
+/// Changes the default level, target overrides are left as they are.
+pub fn set_level(level: Level) {
+    FILTER.set_level(level);
+}
+
+/// Steps the default level one more verbose, wrapping back round to errors only after trace.
+pub fn cycle_level() -> Level {
+    FILTER.cycle_level()
+}
+
+pub fn log(level: Level, target: &str, args: Arguments) {
+    let target = target.trim_start_matches(CRATE_PREFIX);
+    if !FILTER.enabled(level, target) {
+        return;
+    }
+
+    if CONSOLE.load(Ordering::Relaxed) {
+        // The screens are narrow, only flag the levels that need attention.
+        match level {
+            Level::Error | Level::Warn => println!("{} {}", level.name(), args),
+            _ => println!("{}", args),
+        }
+    }
+
+    let mut file = FILE.lock().unwrap();
+    if let Some(sink) = file.as_mut() {
+        let line = format!(
+            "{} {:5} {}: {}\n",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            level.name(),
+            target,
+            args
+        );
+        sink.write(&line);
+    }
+}
+
+/// Called every frame from the main loop, pushes out the file sink's buffer now and again.
+pub fn step() {
+    let mut file = FILE.lock().unwrap();
+    if let Some(sink) = file.as_mut()
+        && sink.last_flush.elapsed() >= FILE_FLUSH_INTERVAL
+    {
+        let _ = sink.writer.flush();
+        sink.last_flush = Instant::now();
+    }
+}
+
+pub fn flush() {
+    if let Some(sink) = FILE.lock().unwrap().as_mut() {
+        let _ = sink.writer.flush();
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::logger::log($crate::logger::Level::Error, module_path!(), format_args!($($arg)+))
+    };
+}
+
+// Named so it doesn't clash with the built in `warn` lint attribute, exported as `warn` below.
+macro_rules! log_warn {
+    ($($arg:tt)+) => {
+        $crate::logger::log($crate::logger::Level::Warn, module_path!(), format_args!($($arg)+))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::logger::log($crate::logger::Level::Info, module_path!(), format_args!($($arg)+))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::logger::log($crate::logger::Level::Debug, module_path!(), format_args!($($arg)+))
+    };
+}
+
+pub(crate) use {debug, error, info, log_warn as warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(level: Level, targets: &[(&str, Level)]) -> Filter {
+        let filter = Filter::new(level);
+        let targets = targets.iter().map(|(target, level)| (target.to_string(), *level)).collect();
+        filter.set_targets(&targets);
+        filter
+    }
+
+    #[test]
+    fn logs_the_default_level_and_above() {
+        let filter = filter(Level::Warn, &[]);
+        assert!(filter.enabled(Level::Error, "handler"));
+        assert!(filter.enabled(Level::Warn, "handler"));
+        assert!(!filter.enabled(Level::Info, "handler"));
+        assert!(!filter.enabled(Level::Trace, "handler"));
+    }
+
+    #[test]
+    fn the_most_specific_override_wins() {
+        let filter = filter(
+            Level::Info,
+            &[("handler", Level::Debug), ("handler::reader", Level::Error), ("site_3ds::api", Level::Warn)],
+        );
+        assert!(filter.enabled(Level::Debug, "handler"));
+        assert!(filter.enabled(Level::Debug, "handler::writer"));
+        assert!(!filter.enabled(Level::Warn, "handler::reader"));
+        assert!(!filter.enabled(Level::Warn, "handler::reader::chunks"));
+        assert!(!filter.enabled(Level::Info, "api"));
+        assert!(filter.enabled(Level::Info, "event_loop"));
+        assert!(!filter.enabled(Level::Debug, "event_loop"));
+    }
+
+    #[test]
+    fn overrides_only_cover_whole_modules() {
+        let filter = filter(Level::Info, &[("handler", Level::Debug)]);
+        assert!(!filter.enabled(Level::Debug, "handler_pool"));
+        assert!(!filter.enabled(Level::Debug, "handlers::x"));
+        assert!(!filter.enabled(Level::Debug, "hand"));
+        assert!(is_within("handler::reader", "handler"));
+        assert!(!is_within("handler_x", "handler"));
+    }
+
+    #[test]
+    fn cycling_wraps_round_after_trace() {
+        let filter = filter(Level::Info, &[]);
+        assert_eq!(filter.cycle_level(), Level::Debug);
+        assert_eq!(filter.cycle_level(), Level::Trace);
+        assert_eq!(filter.cycle_level(), Level::Error);
+        assert!(!filter.enabled(Level::Warn, "handler"));
+        assert_eq!(filter.cycle_level(), Level::Warn);
+    }
+
+    #[test]
+    fn max_level_covers_the_most_verbose_override() {
+        let verbose = filter(Level::Warn, &[("api", Level::Trace)]);
+        assert_eq!(verbose.max_level.load(Ordering::Relaxed), Level::Trace as u8);
+        assert!(verbose.enabled(Level::Trace, "api"));
+
+        let quiet = filter(Level::Warn, &[("api", Level::Error)]);
+        assert_eq!(quiet.max_level.load(Ordering::Relaxed), Level::Warn as u8);
+        quiet.set_level(Level::Debug);
+        assert_eq!(quiet.max_level.load(Ordering::Relaxed), Level::Debug as u8);
+        assert!(quiet.enabled(Level::Debug, "handler"));
+    }
+
+    #[test]
+    fn calls_above_max_level_skip_the_targets() {
+        let filter = filter(Level::Info, &[("api", Level::Debug)]);
+        // With the targets locked for writing, only the fast path can answer without blocking.
+        let _targets = filter.targets.write().unwrap();
+        assert!(!filter.enabled(Level::Trace, "api"));
+    }
+}
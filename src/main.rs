@@ -1,49 +1,13 @@
-#![feature(thread_id_value)]
-#![feature(duration_constructors)]
-mod api;
-mod database;
-mod handler;
-mod http_utils;
+//! The 3DS build runs with the handheld's screens and buttons, any other build in a terminal.
 
-use std::sync::{Arc, Mutex};
-
-use ctru::prelude::*;
-use database::Database;
-use handler::Handler;
-
-const WORKER_COUNT: usize = 3;
+#[cfg(target_os = "horizon")]
+mod horizon;
+#[cfg(not(target_os = "horizon"))]
+mod terminal;
 
 fn main() {
-    ctru::applets::error::set_panic_hook(true);
-
-    let gfx = Gfx::new().unwrap();
-    let mut hid = Hid::new().unwrap();
-    let apt = Apt::new().unwrap();
-    let soc = Soc::new().unwrap();
-
-    {
-        let _bottom_console = Console::new(gfx.bottom_screen.borrow_mut());
-        println!("Serving at {}/\n", soc.host_address());
-        println!("Running {} workers", WORKER_COUNT);
-        println!("Press Start to exit");
-    }
-
-    let _top_console = Console::new(gfx.top_screen.borrow_mut());
-    let db = Arc::new(Mutex::new(Database::new()));
-    let mut handler = Handler::new(db.clone(), WORKER_COUNT);
-
-
-    while apt.main_loop() {
-        hid.scan_input();
-        if hid.keys_down().contains(KeyPad::START) {
-            break;
-        };
-
-        handler.step();
-        db.lock().unwrap().step();
-
-        gfx.wait_for_vblank();
-    }
-    println!("Attempting to stop workers");
-    handler.stop_workers();
+    #[cfg(target_os = "horizon")]
+    horizon::main();
+    #[cfg(not(target_os = "horizon"))]
+    terminal::main();
 }
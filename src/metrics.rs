@@ -0,0 +1,395 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    database::Database,
+    http_utils::{Cut, Request, Response, ResponseBody, content_types},
+    timing::{self, Phase, Timings},
+};
+
+// Upper bounds in seconds, from a quick status check up to a video going out over slow wifi.
+const PHASE_BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Escapes a label value for the text format, where a quote, backslash or newline would end it early.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[derive(Default)]
+struct Histogram {
+    // Observations that fell in each bucket (not cumulative), with one extra for anything over the last.
+    buckets: [u64; PHASE_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = PHASE_BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(PHASE_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+struct QueueDepth {
+    len: usize,
+    max: usize,
+}
+
+/// The shared worker pool, reserved workers aren't counted.
+#[derive(Default)]
+pub struct WorkerPool {
+    pub current: usize,
+    pub busy: usize,
+    pub min: usize,
+    pub max: usize,
+}
+
+/// Counters shared between the handler and the workers, rendered in the Prometheus text format.
+pub struct Metrics {
+    ready_queue_percent: u8,
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    queue_depths: Mutex<BTreeMap<&'static str, QueueDepth>>,
+    worker_busy: Mutex<BTreeMap<usize, Duration>>,
+    bytes_sent: Mutex<BTreeMap<String, u64>>,
+    connections_cut: Mutex<BTreeMap<&'static str, u64>>,
+    panics: Mutex<BTreeMap<&'static str, u64>>,
+    worker_pool: Mutex<WorkerPool>,
+    phases: Mutex<[Histogram; Phase::ALL.len()]>,
+}
+
+impl Metrics {
+    pub fn new(ready_queue_percent: u8) -> Self {
+        Self {
+            ready_queue_percent,
+            requests: Mutex::default(),
+            queue_depths: Mutex::default(),
+            worker_busy: Mutex::default(),
+            bytes_sent: Mutex::default(),
+            connections_cut: Mutex::default(),
+            panics: Mutex::default(),
+            worker_pool: Mutex::default(),
+            phases: Mutex::default(),
+        }
+    }
+
+    pub fn record_request(&self, route: &str, status: u16) {
+        let mut requests = self.requests.lock().unwrap();
+        *requests.entry((route.to_string(), status)).or_insert(0) += 1;
+    }
+
+    pub fn set_queue_depth(&self, queue: &'static str, len: usize, max: usize) {
+        let mut queue_depths = self.queue_depths.lock().unwrap();
+        queue_depths.insert(queue, QueueDepth { len, max });
+    }
+
+    pub fn add_worker_busy(&self, worker_id: usize, busy: Duration) {
+        let mut worker_busy = self.worker_busy.lock().unwrap();
+        *worker_busy.entry(worker_id).or_default() += busy;
+    }
+
+    pub fn add_bytes_sent(&self, encoding: &str, bytes: usize) {
+        let mut bytes_sent = self.bytes_sent.lock().unwrap();
+        *bytes_sent.entry(encoding.to_string()).or_insert(0) += bytes as u64;
+    }
+
+    pub fn set_worker_pool(&self, worker_pool: WorkerPool) {
+        *self.worker_pool.lock().unwrap() = worker_pool;
+    }
+
+    pub fn record_timings(&self, timings: &Timings) {
+        let mut phases = self.phases.lock().unwrap();
+        for (phase, duration) in timings.iter() {
+            phases[phase as usize].observe(duration.as_secs_f64());
+        }
+    }
+
+    pub fn record_cut(&self, cut: Cut) {
+        let mut connections_cut = self.connections_cut.lock().unwrap();
+        *connections_cut.entry(cut.name()).or_insert(0) += 1;
+    }
+
+    /// `place` is one of `request`, `reader` or `worker`.
+    pub fn record_panic(&self, place: &'static str) {
+        *self.panics.lock().unwrap().entry(place).or_insert(0) += 1;
+    }
+
+    /// Every queue must be below `ready_queue_percent` of its maximum size.
+    pub fn queues_ready(&self) -> bool {
+        let queue_depths = self.queue_depths.lock().unwrap();
+        queue_depths
+            .values()
+            .all(|depth| depth.len * 100 < depth.max * self.ready_queue_percent as usize)
+    }
+
+    pub fn render(&self, db: &Database) -> String {
+        let mut out = String::with_capacity(2048);
+
+        out.push_str("# HELP site3ds_requests_total Requests answered by route and status.\n");
+        out.push_str("# TYPE site3ds_requests_total counter\n");
+        for ((route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "site3ds_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                escape_label(route),
+                status,
+                count
+            );
+        }
+
+        out.push_str("# HELP site3ds_queue_depth Jobs waiting in each queue.\n");
+        out.push_str("# TYPE site3ds_queue_depth gauge\n");
+        for (queue, depth) in self.queue_depths.lock().unwrap().iter() {
+            let _ = writeln!(out, "site3ds_queue_depth{{queue=\"{}\"}} {}", queue, depth.len);
+        }
+
+        out.push_str("# HELP site3ds_worker_busy_seconds_total Time each worker spent handling jobs.\n");
+        out.push_str("# TYPE site3ds_worker_busy_seconds_total counter\n");
+        for (worker_id, busy) in self.worker_busy.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "site3ds_worker_busy_seconds_total{{worker=\"{}\"}} {:.6}",
+                worker_id,
+                busy.as_secs_f64()
+            );
+        }
+
+        {
+            let worker_pool = self.worker_pool.lock().unwrap();
+            out.push_str("# HELP site3ds_workers Shared workers running and how many of them are busy.\n");
+            out.push_str("# TYPE site3ds_workers gauge\n");
+            let _ = writeln!(out, "site3ds_workers{{state=\"running\"}} {}", worker_pool.current);
+            let _ = writeln!(out, "site3ds_workers{{state=\"busy\"}} {}", worker_pool.busy);
+            out.push_str("# HELP site3ds_workers_min Fewest shared workers the pool shrinks to.\n");
+            out.push_str("# TYPE site3ds_workers_min gauge\n");
+            let _ = writeln!(out, "site3ds_workers_min {}", worker_pool.min);
+            out.push_str("# HELP site3ds_workers_max Most shared workers the pool grows to.\n");
+            out.push_str("# TYPE site3ds_workers_max gauge\n");
+            let _ = writeln!(out, "site3ds_workers_max {}", worker_pool.max);
+        }
+
+        out.push_str("# HELP site3ds_bytes_sent_total Bytes written to clients by content encoding.\n");
+        out.push_str("# TYPE site3ds_bytes_sent_total counter\n");
+        for (encoding, bytes) in self.bytes_sent.lock().unwrap().iter() {
+            let _ = writeln!(out, "site3ds_bytes_sent_total{{encoding=\"{}\"}} {}", escape_label(encoding), bytes);
+        }
+
+        out.push_str("# HELP site3ds_request_phase_seconds Time requests spent in each phase of being served.\n");
+        out.push_str("# TYPE site3ds_request_phase_seconds histogram\n");
+        for (phase, histogram) in Phase::ALL.iter().zip(self.phases.lock().unwrap().iter()) {
+            let mut cumulative = 0;
+            for (bound, count) in PHASE_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "site3ds_request_phase_seconds_bucket{{phase=\"{}\",le=\"{}\"}} {}",
+                    phase.name(),
+                    bound,
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "site3ds_request_phase_seconds_bucket{{phase=\"{}\",le=\"+Inf\"}} {}",
+                phase.name(),
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "site3ds_request_phase_seconds_sum{{phase=\"{}\"}} {:.6}",
+                phase.name(),
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "site3ds_request_phase_seconds_count{{phase=\"{}\"}} {}",
+                phase.name(),
+                histogram.count
+            );
+        }
+
+        out.push_str("# HELP site3ds_connections_cut_total Connections dropped for being too slow, by reason.\n");
+        out.push_str("# TYPE site3ds_connections_cut_total counter\n");
+        for (reason, count) in self.connections_cut.lock().unwrap().iter() {
+            let _ = writeln!(out, "site3ds_connections_cut_total{{reason=\"{}\"}} {}", reason, count);
+        }
+
+        out.push_str("# HELP site3ds_panics_total Panics caught or recovered from, by where they happened.\n");
+        out.push_str("# TYPE site3ds_panics_total counter\n");
+        for (place, count) in self.panics.lock().unwrap().iter() {
+            let _ = writeln!(out, "site3ds_panics_total{{where=\"{}\"}} {}", place, count);
+        }
+
+        out.push_str("# HELP site3ds_database_dirty_age_seconds Time since the oldest unsaved database change.\n");
+        out.push_str("# TYPE site3ds_database_dirty_age_seconds gauge\n");
+        let _ = writeln!(
+            out,
+            "site3ds_database_dirty_age_seconds {:.3}",
+            db.dirty_age().unwrap_or_default().as_secs_f64()
+        );
+
+        out.push_str("# HELP site3ds_database_save_failures_total Database saves that failed.\n");
+        out.push_str("# TYPE site3ds_database_save_failures_total counter\n");
+        let _ = writeln!(out, "site3ds_database_save_failures_total {}", db.save_failures());
+
+        out
+    }
+}
+
+/// Answers `GET` or `HEAD` for the status routes. `/readyz` fails while any queue is nearly full or the
+/// database can't save, see `Database::is_ready` for what that does and doesn't cover.
+pub fn route<'a>(
+    request: &Request,
+    db: Arc<Mutex<Database>>,
+    metrics: &Metrics,
+) -> Option<Response<'a>> {
+    let mut response = match request.method.as_str() {
+        "GET" | "HEAD" => status_response(request, db, metrics)?,
+        _ => return None,
+    };
+    if request.method == "HEAD" {
+        response.content_length_override = Some(response.body.len());
+        response.body = ResponseBody::Empty;
+    }
+    Some(response)
+}
+
+fn status_response<'a>(request: &Request, db: Arc<Mutex<Database>>, metrics: &Metrics) -> Option<Response<'a>> {
+    match request.path.as_str() {
+        "/healthz" => Some(plain_response(200, "ok")),
+        "/readyz" => {
+            if !timing::timed_lock(&db).is_ready() {
+                Some(plain_response(503, "database unavailable"))
+            } else if !metrics.queues_ready() {
+                Some(plain_response(503, "queues full"))
+            } else {
+                Some(plain_response(200, "ready"))
+            }
+        }
+        "/metrics" => {
+            let body = {
+                let db = timing::timed_lock(&db);
+                metrics.render(&db)
+            };
+            let mut response = Response::new();
+            response.content_type = content_types::PROMETHEUS;
+            response.body = ResponseBody::Owned(body.into_bytes());
+            Some(response)
+        }
+        _ => None,
+    }
+}
+
+fn plain_response<'a>(status: u16, message: &'static str) -> Response<'a> {
+    let mut response = Response::new();
+    response.status = status;
+    response.content_type = content_types::PLAIN;
+    response.body = ResponseBody::Lifetime(message.as_bytes());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+
+    fn database() -> Arc<Mutex<Database>> {
+        let filename = std::env::temp_dir().join(format!("site_3ds_metrics_{}.bin", std::process::id()));
+        Arc::new(Mutex::new(Database::new(&DatabaseConfig {
+            filename: filename.to_string_lossy().into_owned(),
+            ..DatabaseConfig::default()
+        })))
+    }
+
+    fn request(method: &str, path: &str) -> Request {
+        Request::parse(format!("{} {} HTTP/1.1\r\n\r\n", method, path).as_bytes()).unwrap()
+    }
+
+    fn body(response: &Response) -> String {
+        String::from_utf8(response.body.data().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn every_family_has_help_and_type() {
+        let metrics = Metrics::new(80);
+        metrics.record_request("/", 200);
+        metrics.record_cut(Cut::ReadTimeout);
+        let rendered = metrics.render(&database().lock().unwrap());
+
+        let lines: Vec<_> = rendered.lines().collect();
+        for (index, line) in lines.iter().enumerate() {
+            if let Some(family) = line.strip_prefix("# TYPE ") {
+                let name = family.split(' ').next().unwrap();
+                assert!(lines[index - 1].starts_with(&format!("# HELP {} ", name)), "{}", line);
+                assert!(["counter", "gauge", "histogram"].contains(&family.split(' ').nth(1).unwrap()), "{}", line);
+            } else if !line.starts_with("# HELP ") {
+                let name = line.split(['{', ' ']).next().unwrap();
+                assert!(name.starts_with("site3ds_"), "{}", line);
+            }
+        }
+        assert!(lines.contains(&"site3ds_requests_total{route=\"/\",status=\"200\"} 1"));
+        assert!(lines.contains(&"site3ds_connections_cut_total{reason=\"read_timeout\"} 1"));
+        assert!(lines.contains(&"site3ds_request_phase_seconds_bucket{phase=\"parse\",le=\"+Inf\"} 0"));
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let metrics = Metrics::new(80);
+        metrics.record_request("a\"b\\c\nd", 404);
+        metrics.add_bytes_sent("x\"y", 10);
+        let rendered = metrics.render(&database().lock().unwrap());
+        assert!(rendered.contains("site3ds_requests_total{route=\"a\\\"b\\\\c\\nd\",status=\"404\"} 1\n"), "{}", rendered);
+        assert!(rendered.contains("site3ds_bytes_sent_total{encoding=\"x\\\"y\"} 10\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn ready_until_a_queue_reaches_the_threshold() {
+        let metrics = Metrics::new(80);
+        assert!(metrics.queues_ready());
+        metrics.set_queue_depth("connections", 7, 10);
+        metrics.set_queue_depth("html", 0, 10);
+        assert!(metrics.queues_ready());
+        metrics.set_queue_depth("html", 8, 10);
+        assert!(!metrics.queues_ready());
+    }
+
+    #[test]
+    fn status_routes() {
+        let db = database();
+        let metrics = Metrics::new(80);
+        let healthz = route(&request("GET", "/healthz"), db.clone(), &metrics).unwrap();
+        assert_eq!((healthz.status, body(&healthz).as_str()), (200, "ok"));
+
+        let ready = route(&request("GET", "/readyz"), db.clone(), &metrics).unwrap();
+        assert_eq!((ready.status, body(&ready).as_str()), (200, "ready"));
+        metrics.set_queue_depth("asset", 10, 10);
+        let full = route(&request("GET", "/readyz"), db.clone(), &metrics).unwrap();
+        assert_eq!((full.status, body(&full).as_str()), (503, "queues full"));
+
+        let rendered = route(&request("GET", "/metrics"), db.clone(), &metrics).unwrap();
+        assert_eq!(rendered.content_type, content_types::PROMETHEUS);
+        assert!(body(&rendered).starts_with("# HELP site3ds_requests_total"));
+
+        assert!(route(&request("POST", "/healthz"), db.clone(), &metrics).is_none());
+        assert!(route(&request("GET", "/statusz"), db, &metrics).is_none());
+    }
+
+    #[test]
+    fn head_is_answered_like_get_without_a_body() {
+        let metrics = Metrics::new(80);
+        let head = route(&request("HEAD", "/healthz"), database(), &metrics).unwrap();
+        assert_eq!(head.status, 200);
+        assert_eq!(head.content_length_override, Some(2));
+        assert!(head.body.data().is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+use std::cell::Cell;
+use std::sync::Arc;
+
+use crate::errors;
+use crate::handler::Router;
+use crate::http_utils::{Request, Response};
+use crate::logger::error;
+use crate::panics;
+
+/// Behaviour that wraps routing for every request, such as adding headers or answering some requests itself.
+pub trait Middleware: Send + Sync {
+    /// Runs before routing. Returning a response skips routing and the `before` of everything later in the
+    /// chain.
+    fn before(&self, _request: &Request) -> Option<Response<'static>> {
+        None
+    }
+
+    /// Runs once there is a response, in the reverse of the order `before` ran.
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+}
+
+// Lets a middleware also be used outside the chain, as the security headers are for unparsed requests.
+impl<M: Middleware + ?Sized> Middleware for Arc<M> {
+    fn before(&self, request: &Request) -> Option<Response<'static>> {
+        (**self).before(request)
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        (**self).after(request, response)
+    }
+}
+
+/// An ordered list of middleware with the router at the end.
+pub struct Chain {
+    middleware: Vec<Box<dyn Middleware>>,
+    router: Router,
+}
+
+impl Chain {
+    pub fn new(router: Router) -> Self {
+        Self {
+            middleware: vec![],
+            router,
+        }
+    }
+
+    /// Adds `middleware` inside everything added so far, so its `before` runs later and its `after` earlier.
+    pub fn with(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// A panic anywhere along the chain is logged and answered with a bare 500. The `after` of everything
+    /// entered still runs on it, each on its own in case that is what panicked, so the 500 keeps its headers.
+    pub fn handle(&self, request: &Request) -> Response<'static> {
+        let entered = Cell::new(0);
+        panics::catch(|| self.run(request, &entered)).unwrap_or_else(|panic| {
+            error!(
+                "Panic handling {} {} from {}: {}",
+                request.method,
+                request.path,
+                request.client_ip(),
+                panic
+            );
+            self.router.metrics().record_panic("request");
+            let mut response = errors::response(500, Some(request));
+            for middleware in self.middleware[..entered.get()].iter().rev() {
+                if let Err(panic) = panics::catch(|| middleware.after(request, &mut response)) {
+                    error!("Panic in middleware answering {} with a 500: {}", request.path, panic);
+                }
+            }
+            response
+        })
+    }
+
+    /// `entered` counts the middleware whose `before` has been called, for `handle` to unwind after a panic.
+    fn run(&self, request: &Request, entered: &Cell<usize>) -> Response<'static> {
+        let mut answered = None;
+        for middleware in &self.middleware {
+            entered.set(entered.get() + 1);
+            answered = middleware.before(request);
+            if answered.is_some() {
+                break;
+            }
+        }
+
+        let mut response = answered.unwrap_or_else(|| self.router.route(request));
+        for middleware in self.middleware[..entered.get()].iter().rev() {
+            middleware.after(request, &mut response);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::database::Database;
+    use crate::metrics::Metrics;
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Does {
+        Nothing,
+        Answer,
+        PanicBefore,
+        PanicAfter,
+    }
+
+    /// Writes down every hook it runs and tags each response it sees on the way out.
+    struct Recorder {
+        name: &'static str,
+        does: Does,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Recorder {
+        fn before(&self, _request: &Request) -> Option<Response<'static>> {
+            self.log.lock().unwrap().push(format!("{} before", self.name));
+            match self.does {
+                Does::Answer => {
+                    let mut response = Response::new();
+                    response.status = 204;
+                    Some(response)
+                }
+                Does::PanicBefore => panic!("{} before", self.name),
+                _ => None,
+            }
+        }
+
+        fn after(&self, _request: &Request, response: &mut Response) {
+            self.log.lock().unwrap().push(format!("{} after", self.name));
+            if self.does == Does::PanicAfter {
+                panic!("{} after", self.name);
+            }
+            response.headers.push(format!("X-{}: {}", self.name, response.status));
+        }
+    }
+
+    fn chain(middleware: &[(&'static str, Does)]) -> (Chain, Arc<Mutex<Vec<String>>>) {
+        let filename = std::env::temp_dir().join(format!("site_3ds_middleware_{}.bin", std::process::id()));
+        let db = Database::new(&DatabaseConfig {
+            filename: filename.to_string_lossy().into_owned(),
+            ..DatabaseConfig::default()
+        });
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut chain = Chain::new(Router::new(Arc::new(Mutex::new(db)), Arc::new(Metrics::new(80))));
+        for &(name, does) in middleware {
+            chain = chain.with(Recorder {
+                name,
+                does,
+                log: log.clone(),
+            });
+        }
+        (chain, log)
+    }
+
+    fn handle(chain: &Chain) -> Response<'static> {
+        chain.handle(&Request::parse(b"GET /missing HTTP/1.1\r\n\r\n").unwrap())
+    }
+
+    fn log(log: &Mutex<Vec<String>>) -> Vec<String> {
+        log.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn after_runs_in_the_reverse_of_before() {
+        let (chain, calls) = chain(&[("a", Does::Nothing), ("b", Does::Nothing), ("c", Does::Nothing)]);
+        let response = handle(&chain);
+        assert_eq!(response.status, 404);
+        assert_eq!(
+            log(&calls),
+            ["a before", "b before", "c before", "c after", "b after", "a after"]
+        );
+        assert!(response.headers.ends_with(&["X-c: 404".to_string(), "X-b: 404".into(), "X-a: 404".into()]));
+    }
+
+    #[test]
+    fn answering_skips_the_rest_of_the_chain() {
+        let (chain, calls) = chain(&[("a", Does::Nothing), ("b", Does::Answer), ("c", Does::Nothing)]);
+        let response = handle(&chain);
+        // The router would have said 404.
+        assert_eq!(response.status, 204);
+        assert_eq!(log(&calls), ["a before", "b before", "b after", "a after"]);
+        assert_eq!(response.headers, ["X-b: 204", "X-a: 204"]);
+    }
+
+    #[test]
+    fn a_panic_before_is_answered_with_a_500_that_still_goes_through_after() {
+        let (chain, calls) = chain(&[("a", Does::Nothing), ("b", Does::PanicBefore), ("c", Does::Nothing)]);
+        let response = handle(&chain);
+        assert_eq!(response.status, 500);
+        assert_eq!(log(&calls), ["a before", "b before", "b after", "a after"]);
+        assert!(response.headers.ends_with(&["X-b: 500".to_string(), "X-a: 500".into()]));
+    }
+
+    #[test]
+    fn a_panic_after_skips_only_the_middleware_that_panicked() {
+        let (chain, calls) = chain(&[("a", Does::Nothing), ("b", Does::PanicAfter), ("c", Does::Nothing)]);
+        let response = handle(&chain);
+        assert_eq!(response.status, 500);
+        assert_eq!(
+            log(&calls),
+            ["a before", "b before", "c before", "c after", "b after", "c after", "b after", "a after"]
+        );
+        assert!(response.headers.ends_with(&["X-c: 500".to_string(), "X-a: 500".into()]));
+        assert!(!response.headers.iter().any(|header| header.starts_with("X-b")));
+    }
+}
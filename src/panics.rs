@@ -0,0 +1,138 @@
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    panic::{self, AssertUnwindSafe, PanicHookInfo},
+    thread,
+};
+
+use crate::logger::error;
+
+thread_local! {
+    // Set while running inside `catch`, where a panic is expected to be dealt with by the caller.
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    static LAST_PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn describe(info: &PanicHookInfo) -> String {
+    let message = payload_message(info.payload());
+    match info.location() {
+        Some(location) => format!("{} at {}:{}", message, location.file(), location.line()),
+        None => message,
+    }
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Wraps whatever hook is installed (the ctru error applet) so it only sees panics on the main thread that
+/// nothing is going to catch. A panic inside `catch` is kept for `catch` to report, one on any other thread is
+/// logged, and the server carries on either way.
+pub fn install_hook() {
+    let fallback = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if CATCHING.get() {
+            LAST_PANIC.set(Some(describe(info)));
+        } else if thread::current().name() != Some("main") {
+            error!("Thread {} panicked: {}", thread::current().id().as_u64(), describe(info));
+        } else {
+            fallback(info);
+        }
+    }));
+}
+
+/// Runs `f`, turning a panic into an `Err` describing it.
+pub fn catch<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    let was_catching = CATCHING.replace(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.set(was_catching);
+    result.map_err(|payload| LAST_PANIC.take().unwrap_or_else(|| payload_message(payload.as_ref())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex, mpsc};
+
+    use super::*;
+    use crate::config::DatabaseConfig;
+    use crate::database::{self, Database};
+    use crate::handler::Router;
+    use crate::http_utils::{Request, Response};
+    use crate::metrics::Metrics;
+    use crate::middleware::{Chain, Middleware};
+
+    struct PanicOn(&'static str);
+
+    impl Middleware for PanicOn {
+        fn before(&self, request: &Request) -> Option<Response<'static>> {
+            if request.path == self.0 {
+                panic!("asked to panic");
+            }
+            None
+        }
+    }
+
+    fn database(name: &str) -> Arc<Mutex<Database>> {
+        let filename = std::env::temp_dir().join(format!("site_3ds_panics_{}_{}.bin", name, std::process::id()));
+        Arc::new(Mutex::new(Database::new(&DatabaseConfig {
+            filename: filename.to_string_lossy().into_owned(),
+            ..DatabaseConfig::default()
+        })))
+    }
+
+    #[test]
+    fn catch_describes_the_panic() {
+        assert_eq!(catch(|| 1), Ok(1));
+        assert_eq!(catch(|| panic!("at {}", "once")), Err::<(), _>("at once".to_string()));
+        assert!(!CATCHING.get());
+    }
+
+    #[test]
+    fn a_worker_survives_a_panicking_request() {
+        let metrics = Arc::new(Metrics::new(80));
+        let chain = Chain::new(Router::new(database("worker"), metrics)).with(PanicOn("/panic"));
+        let (requests, jobs) = mpsc::channel::<&'static str>();
+        let (statuses, answered) = mpsc::channel();
+        // Handles requests one after another on one thread, the same as `Worker::work`.
+        let worker = std::thread::spawn(move || {
+            for path in jobs {
+                let request = Request::parse(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes()).unwrap();
+                statuses.send(chain.handle(&request).status).unwrap();
+            }
+        });
+
+        for (path, status) in [("/panic", 500), ("/missing", 404), ("/panic", 500), ("/missing", 404)] {
+            requests.send(path).unwrap();
+            assert_eq!(answered.recv().unwrap(), status, "{}", path);
+            assert!(!worker.is_finished());
+        }
+        drop(requests);
+        assert!(worker.join().is_ok());
+    }
+
+    #[test]
+    fn the_next_caller_recovers_a_poisoned_database() {
+        let db = database("poisoned");
+        database::lock(&db).add_review_rating(1, 5);
+        let poisoner = db.clone();
+        let panicked = std::thread::spawn(move || {
+            let mut db = database::lock(&poisoner);
+            db.add_review_rating(1, 3);
+            panic!("holding the database");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(db.is_poisoned());
+
+        assert_eq!(database::lock(&db).get_review_rating(1), 8);
+        assert!(!db.is_poisoned());
+        database::lock(&db).add_review_rating(1, 1);
+        assert_eq!(database::lock(&db).get_review_rating(1), 9);
+    }
+}
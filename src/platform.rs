@@ -0,0 +1,271 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{Config, ServerMode};
+use crate::database::{self, Database};
+use crate::event_loop::EventLoop;
+use crate::handler::{Handler, Server};
+use crate::logger;
+
+/// Something asked of the server through the buttons, or whatever stands in for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Input {
+    CycleLogLevel,
+    Exit,
+}
+
+/// Where `println!` output goes. The 3DS has a screen for each, elsewhere they can be one and the same.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Screen {
+    /// What the server is doing and how to control it, shown while starting up and shutting down.
+    Status,
+    /// The log while serving.
+    Log,
+}
+
+/// What the main loop needs from the system it runs on.
+pub trait Platform {
+    /// False once the system wants the app to close, the signal to shut down without being asked to.
+    fn is_running(&mut self) -> bool;
+
+    /// What was asked for since the last call, in the order it should be handled.
+    fn inputs(&mut self) -> Vec<Input>;
+
+    /// Waits for the next frame.
+    fn tick(&mut self);
+
+    fn show(&mut self, screen: Screen);
+
+    /// The address other machines on the network can reach the server at.
+    fn host_address(&self) -> IpAddr;
+
+    /// How to give `input`, e.g. "Press Start".
+    fn prompt(&self, input: Input) -> &'static str;
+}
+
+/// Everything from loading the config to saving the database for the last time.
+pub fn run(platform: &mut impl Platform) {
+    platform.show(Screen::Status);
+    let (config, report) = Config::load();
+    logger::init(&config.logging);
+    report.log();
+    println!("Serving at {}:{}/\n", platform.host_address(), config.server.port);
+    config.print();
+    println!("{} to change log level", platform.prompt(Input::CycleLogLevel));
+    println!("{} to exit", platform.prompt(Input::Exit));
+
+    platform.show(Screen::Log);
+    let db = Arc::new(Mutex::new(Database::new(&config.database)));
+    let mut server: Box<dyn Server> = match config.server.mode {
+        ServerMode::Threaded => Box::new(Handler::new(db.clone(), &config)),
+        ServerMode::EventLoop => Box::new(EventLoop::new(db.clone(), &config)),
+    };
+
+    main_loop(platform, server.as_mut(), &db);
+    shut_down(
+        platform,
+        server.as_mut(),
+        &db,
+        Duration::from_millis(config.server.shutdown_timeout_ms),
+    );
+}
+
+/// Steps the server once a frame until the platform closes or is asked to exit.
+pub fn main_loop(platform: &mut impl Platform, server: &mut dyn Server, db: &Mutex<Database>) {
+    while platform.is_running() {
+        for input in platform.inputs() {
+            match input {
+                Input::Exit => return,
+                Input::CycleLogLevel => println!("Log level: {:?}", logger::cycle_level()),
+            }
+        }
+
+        server.step();
+        database::lock(db).step();
+        logger::step();
+
+        platform.tick();
+    }
+}
+
+pub fn shut_down(platform: &mut impl Platform, server: &mut dyn Server, db: &Mutex<Database>, timeout: Duration) {
+    platform.show(Screen::Status);
+    println!("Shutting down");
+    server.shutdown(timeout);
+    // Only once every worker has stopped, so nothing can change after the save.
+    println!("Saving database");
+    database::lock(db).flush();
+    logger::flush();
+    println!("Goodbye");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+    use crate::config::DatabaseConfig;
+
+    /// Plays back a script of inputs, one frame at a time, and records what the loop did with it.
+    #[derive(Default)]
+    struct MockPlatform {
+        frames: VecDeque<Vec<Input>>,
+        /// Frames left before the system closes the app, `None` for never.
+        closes_after: Option<usize>,
+        ticks: usize,
+        screens: Vec<Screen>,
+    }
+
+    impl MockPlatform {
+        fn scripted(frames: impl IntoIterator<Item = Vec<Input>>) -> Self {
+            Self {
+                frames: frames.into_iter().collect(),
+                ..Self::default()
+            }
+        }
+    }
+
+    impl Platform for MockPlatform {
+        fn is_running(&mut self) -> bool {
+            self.closes_after.is_none_or(|frames| self.ticks < frames)
+        }
+
+        fn inputs(&mut self) -> Vec<Input> {
+            self.frames.pop_front().unwrap_or_default()
+        }
+
+        fn tick(&mut self) {
+            self.ticks += 1;
+        }
+
+        fn show(&mut self, screen: Screen) {
+            self.screens.push(screen);
+        }
+
+        fn host_address(&self) -> IpAddr {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        }
+
+        fn prompt(&self, _input: Input) -> &'static str {
+            "Mock"
+        }
+    }
+
+    /// Counts a visit every step and a rating on shutdown, so the saved database shows what ran before it.
+    struct MockServer {
+        db: Arc<Mutex<Database>>,
+        steps: usize,
+        shutdowns: Vec<Duration>,
+    }
+
+    impl Server for MockServer {
+        fn step(&mut self) {
+            self.steps += 1;
+            database::lock(&self.db).add_visit(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, self.steps as u8)));
+        }
+
+        fn shutdown(&mut self, timeout: Duration) {
+            self.shutdowns.push(timeout);
+            database::lock(&self.db).add_review_rating(1, 1);
+        }
+
+        fn local_addr(&self) -> SocketAddr {
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 0))
+        }
+    }
+
+    fn database_config(name: &str) -> DatabaseConfig {
+        let filename = std::env::temp_dir().join(format!("site_3ds_{}_{}.bin", name, std::process::id()));
+        let _ = std::fs::remove_file(&filename);
+        DatabaseConfig {
+            filename: filename.to_string_lossy().into_owned(),
+            ..DatabaseConfig::default()
+        }
+    }
+
+    fn server(config: &DatabaseConfig) -> MockServer {
+        MockServer {
+            db: Arc::new(Mutex::new(Database::new(config))),
+            steps: 0,
+            shutdowns: vec![],
+        }
+    }
+
+    #[test]
+    fn steps_every_frame_until_exit() {
+        let mut platform = MockPlatform::scripted([vec![], vec![Input::CycleLogLevel], vec![], vec![Input::Exit]]);
+        let mut server = server(&database_config("steps"));
+        let db = server.db.clone();
+
+        main_loop(&mut platform, &mut server, &db);
+        assert_eq!(server.steps, 3);
+        assert_eq!(platform.ticks, 3);
+        assert!(server.shutdowns.is_empty());
+    }
+
+    #[test]
+    fn exit_wins_over_later_inputs_in_the_same_frame() {
+        let mut platform = MockPlatform::scripted([vec![Input::Exit, Input::CycleLogLevel]]);
+        let mut server = server(&database_config("exit_first"));
+        let db = server.db.clone();
+
+        main_loop(&mut platform, &mut server, &db);
+        assert_eq!(server.steps, 0);
+    }
+
+    #[test]
+    fn stops_when_the_system_closes_the_app() {
+        let mut platform = MockPlatform {
+            closes_after: Some(5),
+            ..MockPlatform::default()
+        };
+        let mut server = server(&database_config("closes"));
+        let db = server.db.clone();
+
+        main_loop(&mut platform, &mut server, &db);
+        assert_eq!(server.steps, 5);
+    }
+
+    #[test]
+    fn saves_the_database_after_the_server_stops() {
+        let config = database_config("shut_down");
+        let mut platform = MockPlatform::scripted([vec![], vec![], vec![Input::Exit]]);
+        let mut server = server(&config);
+        let db = server.db.clone();
+
+        main_loop(&mut platform, &mut server, &db);
+        shut_down(&mut platform, &mut server, &db, Duration::from_millis(1234));
+        assert_eq!(server.shutdowns, [Duration::from_millis(1234)]);
+        assert_eq!(platform.screens, [Screen::Status]);
+
+        let saved = Database::new(&config);
+        assert_eq!(saved.get_visits(), 2);
+        assert_eq!(saved.get_review_rating(1), 1);
+        let _ = std::fs::remove_file(&config.filename);
+    }
+
+    #[test]
+    fn saves_the_database_when_the_system_closes_the_app() {
+        let config = database_config("closed");
+        let mut platform = MockPlatform {
+            closes_after: Some(3),
+            ..MockPlatform::default()
+        };
+        let mut server = server(&config);
+        let db = server.db.clone();
+
+        main_loop(&mut platform, &mut server, &db);
+        // Too soon for the loop to have saved, the visits are only in memory.
+        assert!(database::lock(&db).dirty_age().is_some());
+        assert_eq!(Database::new(&config).get_visits(), 0);
+        shut_down(&mut platform, &mut server, &db, Duration::from_millis(10));
+        assert_eq!(server.shutdowns.len(), 1);
+
+        let saved = Database::new(&config);
+        assert_eq!(saved.get_visits(), 3);
+        assert_eq!(saved.get_review_rating(1), 1);
+        let _ = std::fs::remove_file(&config.filename);
+    }
+}
@@ -0,0 +1,69 @@
+use std::{io, os::fd::RawFd, time::Duration};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interest {
+    Read,
+    Write,
+}
+
+/// Waits up to `timeout` for any of `sockets` to be ready for what it is wanted for, with poll(2), which
+/// libctru provides along with the rest of its BSD sockets. Returns which of them are. A socket that failed or
+/// was hung up on counts as ready, so the read or write that follows finds out what happened.
+pub fn ready(sockets: &[(RawFd, Interest)], timeout: Duration) -> io::Result<Vec<bool>> {
+    let mut fds: Vec<libc::pollfd> = sockets
+        .iter()
+        .map(|(fd, interest)| libc::pollfd {
+            fd: *fd,
+            events: match interest {
+                Interest::Read => libc::POLLIN,
+                Interest::Write => libc::POLLOUT,
+            },
+            revents: 0,
+        })
+        .collect();
+    let timeout = timeout.as_millis().min(i32::MAX as u128) as libc::c_int;
+
+    // SAFETY: `fds` is a live, correctly sized array of pollfds for the length of the call.
+    let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+    match result {
+        -1 => {
+            let error = io::Error::last_os_error();
+            // Interrupted before anything was ready, the same as timing out.
+            if error.kind() == io::ErrorKind::Interrupted {
+                return Ok(vec![false; sockets.len()]);
+            }
+            Err(error)
+        }
+        _ => Ok(fds.iter().map(|fd| fd.revents != 0).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+
+    #[test]
+    fn finds_the_sockets_with_something_to_do() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let sockets = [(server.as_raw_fd(), Interest::Read), (client.as_raw_fd(), Interest::Write)];
+        assert_eq!(ready(&sockets, Duration::ZERO).unwrap(), [false, true]);
+
+        client.write_all(b"GET /").unwrap();
+        assert_eq!(ready(&sockets[..1], Duration::from_secs(5)).unwrap(), [true]);
+    }
+
+    #[test]
+    fn times_out_when_nothing_is_ready() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let started = std::time::Instant::now();
+        assert_eq!(ready(&[(listener.as_raw_fd(), Interest::Read)], Duration::from_millis(20)).unwrap(), [false]);
+        assert!(started.elapsed() >= Duration::from_millis(15));
+    }
+}
@@ -0,0 +1,544 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// An address range such as `10.8.0.0/24` or `fd00::/8`. A bare address is treated as a single host.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpCidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+        let network = IpAddr::from_str(address.trim())
+            .map_err(|e| format!("invalid address in {value}: {e}"))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .trim()
+                .parse::<u8>()
+                .map_err(|e| format!("invalid prefix in {value}: {e}"))?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(format!("prefix in {value} is longer than {max_len}"));
+        }
+
+        Ok(IpCidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl TryFrom<String> for IpCidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        IpCidr::from_str(&value)
+    }
+}
+
+impl From<IpCidr> for String {
+    fn from(value: IpCidr) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+pub(crate) fn is_trusted(ip: &IpAddr, trusted_proxies: &[IpCidr]) -> bool {
+    trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// Parses a single hop such as `1.2.3.4`, `1.2.3.4:80`, `"[2001:db8::1]:4711"` or `2001:db8::1`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        let (address, _) = rest.split_once(']')?;
+        return Ipv6Addr::from_str(address).ok().map(IpAddr::V6);
+    }
+    if let Ok(ip) = IpAddr::from_str(node) {
+        return Some(ip);
+    }
+    // An IPv4 address with a port, anything else (`unknown`, `_hidden`) isn't an address.
+    let (address, _) = node.split_once(':')?;
+    Ipv4Addr::from_str(address).ok().map(IpAddr::V4)
+}
+
+/// The `for=` nodes of an RFC 7239 `Forwarded` header, closest to the client first.
+pub fn forwarded_for(value: &str) -> Vec<&str> {
+    let mut nodes = vec![];
+    for element in value.split(',') {
+        for pair in element.split(';') {
+            if let Some((name, node)) = pair.split_once('=')
+                && name.trim().eq_ignore_ascii_case("for")
+            {
+                nodes.push(node.trim());
+            }
+        }
+    }
+    nodes
+}
+
+/// The entries of an `X-Forwarded-For` header, closest to the client first.
+pub fn x_forwarded_for(value: &str) -> Vec<&str> {
+    value.split(',').map(|node| node.trim()).collect()
+}
+
+/// Walks the forwarding chain from the connected peer back towards the client, stopping at the first hop
+/// that isn't a trusted proxy. Headers are only believed when the hop that added them is trusted.
+pub fn resolve_client_ip(peer: IpAddr, chain: &[&str], trusted_proxies: &[IpCidr]) -> IpAddr {
+    let mut client = peer;
+    for node in chain.iter().rev() {
+        if !is_trusted(&client, trusted_proxies) {
+            break;
+        }
+        match parse_node(node) {
+            Some(ip) => client = ip,
+            None => break,
+        }
+    }
+    client
+}
+
+const PROXY_V1_SIGNATURE: &[u8] = b"PROXY ";
+// The longest possible v1 line, `PROXY TCP6` with two full IPv6 addresses and ports.
+const PROXY_V1_MAX_LEN: usize = 107;
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const PROXY_V2_HEADER_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocolMode {
+    /// Never look for a PROXY protocol preamble.
+    #[default]
+    Off,
+    /// Use the preamble when a trusted peer sends one.
+    Optional,
+    /// Like `Optional`, but a trusted peer must send one. A proxy that doesn't would have every visitor behind it
+    /// logged and rate limited as the proxy itself.
+    Strict,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The real client, `None` for `LOCAL`/`UNKNOWN` connections made by the proxy itself.
+    pub source: Option<SocketAddr>,
+    /// Bytes taken up by the preamble, the HTTP request starts after them.
+    pub len: usize,
+}
+
+fn parse_proxy_v1(data: &[u8]) -> Result<ProxyHeader, String> {
+    let end = data
+        .iter()
+        .take(PROXY_V1_MAX_LEN)
+        .position(|byte| *byte == b'\n')
+        .ok_or("PROXY v1 line is not terminated")?;
+    let line = std::str::from_utf8(&data[..end])
+        .map_err(|e| format!("PROXY v1 line is not text: {e}"))?
+        .trim_end_matches('\r');
+
+    let parts: Vec<&str> = line.split(' ').collect();
+    let source = match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip = IpAddr::from_str(source).map_err(|e| format!("invalid PROXY v1 source: {e}"))?;
+            let port = source_port
+                .parse::<u16>()
+                .map_err(|e| format!("invalid PROXY v1 port: {e}"))?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(format!("malformed PROXY v1 line: {line}")),
+    };
+
+    Ok(ProxyHeader {
+        source,
+        len: end + 1,
+    })
+}
+
+fn parse_proxy_v2(data: &[u8]) -> Result<ProxyHeader, String> {
+    if data.len() < PROXY_V2_HEADER_LEN {
+        return Err("PROXY v2 header is truncated".to_string());
+    }
+    let version_command = data[12];
+    if version_command >> 4 != 2 {
+        return Err(format!("unsupported PROXY version {}", version_command >> 4));
+    }
+    let family = data[13];
+    let address_len = u16::from_be_bytes([data[14], data[15]]) as usize;
+    let len = PROXY_V2_HEADER_LEN + address_len;
+    if data.len() < len {
+        return Err("PROXY v2 addresses are truncated".to_string());
+    }
+    let addresses = &data[PROXY_V2_HEADER_LEN..len];
+
+    // LOCAL connections are health checks from the proxy, keep the peer address.
+    if version_command & 0x0F == 0 {
+        return Ok(ProxyHeader { source: None, len });
+    }
+
+    let source = match family >> 4 {
+        // AF_INET
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+        }
+        1 | 2 => return Err("PROXY v2 address block is too short".to_string()),
+        // AF_UNSPEC and AF_UNIX don't carry anything we can use.
+        _ => None,
+    };
+
+    Ok(ProxyHeader { source, len })
+}
+
+/// Looks for a PROXY protocol v1 or v2 preamble at the start of `data`.
+pub fn parse_proxy_header(data: &[u8]) -> Result<Option<ProxyHeader>, String> {
+    if data.starts_with(PROXY_V2_SIGNATURE) {
+        parse_proxy_v2(data).map(Some)
+    } else if data.starts_with(PROXY_V1_SIGNATURE) {
+        parse_proxy_v1(data).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the address the connection is really from and how many bytes of preamble to skip.
+pub fn accept_proxy_header(
+    mode: ProxyProtocolMode,
+    peer: &SocketAddr,
+    data: &[u8],
+    trusted_proxies: &[IpCidr],
+) -> Result<(SocketAddr, usize), String> {
+    if mode == ProxyProtocolMode::Off {
+        return Ok((*peer, 0));
+    }
+
+    let trusted = is_trusted(&peer.ip(), trusted_proxies);
+    match parse_proxy_header(data)? {
+        Some(_) if !trusted => Err(format!("PROXY header from untrusted peer {}", peer.ip())),
+        Some(header) => Ok((header.source.unwrap_or(*peer), header.len)),
+        None if mode == ProxyProtocolMode::Strict && trusted => {
+            Err(format!("missing PROXY header from trusted proxy {}", peer.ip()))
+        }
+        None => Ok((*peer, 0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(value: &str) -> IpCidr {
+        value.parse().unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_matches_up_to_the_prefix_boundary() {
+        let network = cidr("10.8.0.0/24");
+        assert!(network.contains(&ip("10.8.0.0")));
+        assert!(network.contains(&ip("10.8.0.255")));
+        assert!(!network.contains(&ip("10.8.1.0")));
+        assert!(!network.contains(&ip("10.7.255.255")));
+
+        let odd = cidr("192.168.1.64/27");
+        assert!(odd.contains(&ip("192.168.1.95")));
+        assert!(!odd.contains(&ip("192.168.1.96")));
+        assert!(!odd.contains(&ip("192.168.1.63")));
+
+        let host = cidr("10.0.0.1");
+        assert_eq!(host.to_string(), "10.0.0.1/32");
+        assert!(host.contains(&ip("10.0.0.1")));
+        assert!(!host.contains(&ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn cidr_matches_ipv6() {
+        let network = cidr("fd00::/8");
+        assert!(network.contains(&ip("fdff:ffff::1")));
+        assert!(!network.contains(&ip("fe00::1")));
+
+        let network = cidr("2001:db8::/33");
+        assert!(network.contains(&ip("2001:db8:7fff::1")));
+        assert!(!network.contains(&ip("2001:db8:8000::1")));
+
+        assert!(cidr("::1").contains(&ip("::1")));
+        assert!(!cidr("::1").contains(&ip("::2")));
+    }
+
+    #[test]
+    fn cidr_zero_prefix_matches_its_whole_family() {
+        assert!(cidr("0.0.0.0/0").contains(&ip("255.255.255.255")));
+        assert!(cidr("0.0.0.0/0").contains(&ip("1.2.3.4")));
+        assert!(!cidr("0.0.0.0/0").contains(&ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(&ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(&ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn cidr_matches_ipv4_mapped_addresses() {
+        assert!(cidr("10.0.0.0/8").contains(&ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn cidr_rejects_bad_input() {
+        for value in ["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/-1", "10.0.0.0/", "nope", ""] {
+            assert!(value.parse::<IpCidr>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn forwarded_for_nodes() {
+        assert_eq!(
+            forwarded_for(r#"for=192.0.2.43, for="[2001:db8:cafe::17]:4711";proto=https, For=198.51.100.17:80"#),
+            [r#"192.0.2.43"#, r#""[2001:db8:cafe::17]:4711""#, "198.51.100.17:80"]
+        );
+        assert_eq!(forwarded_for("proto=http;by=203.0.113.43"), Vec::<&str>::new());
+        assert_eq!(forwarded_for("for=unknown"), ["unknown"]);
+    }
+
+    #[test]
+    fn nodes_parse_with_quotes_brackets_and_ports() {
+        assert_eq!(parse_node("192.0.2.43"), Some(ip("192.0.2.43")));
+        assert_eq!(parse_node(r#""192.0.2.43:8080""#), Some(ip("192.0.2.43")));
+        assert_eq!(parse_node(r#""[2001:db8:cafe::17]:4711""#), Some(ip("2001:db8:cafe::17")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node("[not-an-address]:80"), None);
+    }
+
+    #[test]
+    fn x_forwarded_for_entries() {
+        assert_eq!(x_forwarded_for(" 203.0.113.7 ,10.0.0.2"), ["203.0.113.7", "10.0.0.2"]);
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let trusted = [cidr("10.0.0.0/8")];
+        assert_eq!(resolve_client_ip(ip("203.0.113.9"), &["198.51.100.1"], &trusted), ip("203.0.113.9"));
+        assert_eq!(resolve_client_ip(ip("10.0.0.1"), &["198.51.100.1"], &[]), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn walks_back_through_trusted_proxies() {
+        let trusted = [cidr("10.0.0.0/8")];
+        let chain = ["198.51.100.1", "10.0.0.3", "10.0.0.2"];
+        assert_eq!(resolve_client_ip(ip("10.0.0.1"), &chain, &trusted), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn stops_at_the_first_untrusted_hop() {
+        let trusted = [cidr("10.0.0.0/8")];
+        // The client put its own entry on the left, only the proxy's entry for it can be believed.
+        let chain = ["1.1.1.1", "203.0.113.9"];
+        assert_eq!(resolve_client_ip(ip("10.0.0.1"), &chain, &trusted), ip("203.0.113.9"));
+
+        let chain = ["1.1.1.1", "203.0.113.9", "10.0.0.2"];
+        assert_eq!(resolve_client_ip(ip("10.0.0.1"), &chain, &trusted), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn stops_at_a_hop_that_is_not_an_address() {
+        let trusted = [cidr("10.0.0.0/8")];
+        let chain = ["198.51.100.1", "unknown", "10.0.0.2"];
+        assert_eq!(resolve_client_ip(ip("10.0.0.1"), &chain, &trusted), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn forwarded_chain_resolves_like_x_forwarded_for() {
+        let trusted = [cidr("10.0.0.0/8"), cidr("fd00::/8")];
+        let chain = forwarded_for(r#"for="[2001:db8::5]:1234", for=fd00::2"#);
+        assert_eq!(resolve_client_ip(ip("10.0.0.1"), &chain, &trusted), ip("2001:db8::5"));
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut data = PROXY_V2_SIGNATURE.to_vec();
+        data.push(0x20 | command);
+        data.push(family);
+        data.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        data.extend_from_slice(addresses);
+        data
+    }
+
+    #[test]
+    fn proxy_v1_tcp4() {
+        let data = b"PROXY TCP4 203.0.113.9 10.0.0.1 51234 8080\r\nGET / HTTP/1.1\r\n\r\n";
+        let header = parse_proxy_header(data).unwrap().unwrap();
+        assert_eq!(header.source, Some("203.0.113.9:51234".parse().unwrap()));
+        assert_eq!(&data[header.len..], b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn proxy_v1_tcp6() {
+        let data = b"PROXY TCP6 2001:db8::5 2001:db8::1 4711 443\r\n";
+        let header = parse_proxy_header(data).unwrap().unwrap();
+        assert_eq!(header.source, Some("[2001:db8::5]:4711".parse().unwrap()));
+        assert_eq!(header.len, data.len());
+    }
+
+    #[test]
+    fn proxy_v1_unknown() {
+        let header = parse_proxy_header(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!(header, ProxyHeader { source: None, len: 15 });
+        // Anything after UNKNOWN is ignored.
+        let header = parse_proxy_header(b"PROXY UNKNOWN 1.2.3.4 5.6.7.8 1 2\r\n").unwrap().unwrap();
+        assert_eq!(header.source, None);
+    }
+
+    #[test]
+    fn proxy_v1_rejects_malformed_lines() {
+        for data in [
+            &b"PROXY TCP4 203.0.113.9 10.0.0.1 51234\r\n"[..],
+            b"PROXY TCP4 not-an-address 10.0.0.1 1 2\r\n",
+            b"PROXY TCP4 203.0.113.9 10.0.0.1 70000 8080\r\n",
+            b"PROXY UDP4 203.0.113.9 10.0.0.1 1 2\r\n",
+            b"PROXY TCP4 \xff 10.0.0.1 1 2\r\n",
+        ] {
+            assert!(parse_proxy_header(data).is_err(), "{:?}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn proxy_v1_line_must_end_within_the_longest_possible_line() {
+        let mut data = b"PROXY TCP4 ".to_vec();
+        data.resize(PROXY_V1_MAX_LEN + 20, b'1');
+        assert!(parse_proxy_header(&data).is_err());
+        data.extend_from_slice(b"\r\n");
+        assert!(parse_proxy_header(&data).is_err());
+        // Nothing more has arrived yet.
+        assert!(parse_proxy_header(b"PROXY TCP4 203.0.113.9").is_err());
+    }
+
+    #[test]
+    fn proxy_v2_tcp4() {
+        let mut data = v2(1, 0x11, &[203, 0, 113, 9, 10, 0, 0, 1, 0xC8, 0x22, 0x1F, 0x90]);
+        data.extend_from_slice(b"GET /");
+        let header = parse_proxy_header(&data).unwrap().unwrap();
+        assert_eq!(header.source, Some("203.0.113.9:51234".parse().unwrap()));
+        assert_eq!(&data[header.len..], b"GET /");
+    }
+
+    #[test]
+    fn proxy_v2_tcp6() {
+        let source: Ipv6Addr = "2001:db8::5".parse().unwrap();
+        let mut addresses = source.octets().to_vec();
+        addresses.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        addresses.extend_from_slice(&[0x12, 0x67, 0x01, 0xBB]);
+        let header = parse_proxy_header(&v2(1, 0x21, &addresses)).unwrap().unwrap();
+        assert_eq!(header.source, Some("[2001:db8::5]:4711".parse().unwrap()));
+        assert_eq!(header.len, PROXY_V2_HEADER_LEN + 36);
+    }
+
+    #[test]
+    fn proxy_v2_local_keeps_the_peer() {
+        // A health check from the proxy, whatever addresses it sends are not a client's.
+        let data = v2(0, 0x11, &[203, 0, 113, 9, 10, 0, 0, 1, 0, 1, 0, 2]);
+        let header = parse_proxy_header(&data).unwrap().unwrap();
+        assert_eq!(header, ProxyHeader { source: None, len: data.len() });
+
+        let data = v2(1, 0x00, &[]);
+        assert_eq!(parse_proxy_header(&data).unwrap().unwrap().source, None);
+    }
+
+    #[test]
+    fn proxy_v2_rejects_truncated_headers() {
+        let data = v2(1, 0x11, &[203, 0, 113, 9, 10, 0, 0, 1, 0, 1, 0, 2]);
+        assert!(parse_proxy_header(&data[..PROXY_V2_HEADER_LEN - 1]).is_err());
+        // The length field claims more than arrived.
+        assert!(parse_proxy_header(&data[..data.len() - 1]).is_err());
+        let mut data = v2(1, 0x11, &[]);
+        data[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(parse_proxy_header(&data).is_err());
+        // Long enough for the header, too short for the family.
+        assert!(parse_proxy_header(&v2(1, 0x11, &[203, 0, 113, 9])).is_err());
+    }
+
+    #[test]
+    fn proxy_v2_rejects_other_versions() {
+        let mut data = v2(1, 0x11, &[0; 12]);
+        data[12] = 0x11;
+        assert!(parse_proxy_header(&data).is_err());
+    }
+
+    #[test]
+    fn no_preamble() {
+        assert_eq!(parse_proxy_header(b"GET / HTTP/1.1\r\n\r\n"), Ok(None));
+        assert_eq!(parse_proxy_header(b""), Ok(None));
+    }
+
+    #[test]
+    fn accepts_preambles_only_from_trusted_peers() {
+        let trusted = [cidr("10.0.0.0/8")];
+        let proxy: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let client: SocketAddr = "203.0.113.9:51234".parse().unwrap();
+        let data = b"PROXY TCP4 203.0.113.9 10.0.0.1 51234 8080\r\nGET /";
+
+        for mode in [ProxyProtocolMode::Optional, ProxyProtocolMode::Strict] {
+            assert_eq!(accept_proxy_header(mode, &proxy, data, &trusted), Ok((client, data.len() - 5)));
+            assert!(accept_proxy_header(mode, &client, data, &trusted).is_err());
+        }
+        // Off leaves the preamble for the request parser to reject.
+        assert_eq!(accept_proxy_header(ProxyProtocolMode::Off, &proxy, data, &trusted), Ok((proxy, 0)));
+    }
+
+    #[test]
+    fn strict_mode_requires_a_preamble_from_trusted_peers() {
+        let trusted = [cidr("10.0.0.0/8")];
+        let proxy: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let client: SocketAddr = "203.0.113.9:51234".parse().unwrap();
+        assert!(accept_proxy_header(ProxyProtocolMode::Strict, &proxy, b"GET /", &trusted).is_err());
+        assert_eq!(accept_proxy_header(ProxyProtocolMode::Optional, &proxy, b"GET /", &trusted), Ok((proxy, 0)));
+
+        // Clients connecting directly are served as themselves either way.
+        for mode in [ProxyProtocolMode::Optional, ProxyProtocolMode::Strict] {
+            assert_eq!(accept_proxy_header(mode, &client, b"GET /", &trusted), Ok((client, 0)));
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex},
+};
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+/// A bounded FIFO that consumers block on until an item arrives or the queue is closed.
+pub struct BlockingQueue<T> {
+    state: Mutex<QueueState<T>>,
+    available: Condvar,
+    max_size: usize,
+}
+
+impl<T> BlockingQueue<T> {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                items: VecDeque::with_capacity(max_size),
+                closed: false,
+            }),
+            available: Condvar::new(),
+            max_size,
+        }
+    }
+
+    /// Adds an item, handing it back if the queue is full or closed.
+    pub fn push(&self, item: T) -> Result<(), T> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.items.len() >= self.max_size {
+            return Err(item);
+        }
+        state.items.push_back(item);
+        drop(state);
+        self.available.notify_one();
+        Ok(())
+    }
+
+    /// Waits for the next item. Returns `None` once the queue has been closed.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(item) = state.items.pop_front() {
+                return Some(item);
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    /// The next item if there is one, without waiting. Returns `None` once the queue has been closed.
+    pub fn try_pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return None;
+        }
+        state.items.pop_front()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Wakes every consumer so they can shut down, handing back whatever was still queued.
+    pub fn close(&self) -> Vec<T> {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        let items = state.items.drain(..).collect();
+        drop(state);
+        self.available.notify_all();
+        items
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().items.len()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn push_hands_the_item_back_when_full() {
+        let queue = BlockingQueue::new(2);
+        assert_eq!(queue.push(1), Ok(()));
+        assert_eq!(queue.push(2), Ok(()));
+        assert_eq!(queue.push(3), Err(3));
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.push(3), Ok(()));
+        assert_eq!(queue.try_pop(), Some(2));
+        assert_eq!(queue.try_pop(), Some(3));
+        assert_eq!(queue.try_pop(), None);
+    }
+
+    #[test]
+    fn close_wakes_a_blocked_pop() {
+        let queue = Arc::new(BlockingQueue::<usize>::new(2));
+        let waiting = std::thread::spawn({
+            let queue = queue.clone();
+            move || queue.pop()
+        });
+        // Long enough for the pop to be waiting in all likelihood, it returns `None` either way.
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(queue.close().is_empty());
+        assert_eq!(waiting.join().unwrap(), None);
+    }
+
+    #[test]
+    fn nothing_comes_out_after_close() {
+        let queue = BlockingQueue::new(4);
+        queue.push(1).unwrap();
+        queue.push(2).unwrap();
+        assert!(!queue.is_closed());
+
+        assert_eq!(queue.close(), [1, 2]);
+        assert!(queue.is_closed());
+        assert_eq!(queue.try_pop(), None);
+        assert_eq!(queue.pop(), None);
+        assert_eq!(queue.push(3), Err(3));
+        assert_eq!(queue.len(), 0);
+    }
+}
@@ -0,0 +1,426 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{config::RateLimitConfig, http_utils::Request, proxy::{self, IpCidr}};
+
+struct Bucket {
+    tokens: f32,
+    updated: Instant,
+}
+
+/// Token buckets keyed by client, never tracking more than `max_clients` at once.
+struct Buckets {
+    per_second: f32,
+    burst: f32,
+    max_clients: usize,
+    buckets: HashMap<IpAddr, Bucket>,
+    // The same clients by when their bucket was last used, so the stalest can be found without a scan.
+    by_age: BTreeSet<(Instant, IpAddr)>,
+}
+
+impl Buckets {
+    fn new(per_second: f32, burst: u32, max_clients: usize) -> Self {
+        Self {
+            per_second,
+            burst: burst as f32,
+            max_clients,
+            buckets: HashMap::with_capacity(max_clients),
+            by_age: BTreeSet::new(),
+        }
+    }
+
+    /// The bucket for `ip`, topped up for the time since it was last used. Forgets the client seen least
+    /// recently to make room for a new one.
+    fn refill(&mut self, ip: IpAddr, now: Instant) -> &mut Bucket {
+        if !self.buckets.contains_key(&ip)
+            && self.buckets.len() >= self.max_clients
+            && let Some((_, oldest)) = self.by_age.pop_first()
+        {
+            self.buckets.remove(&oldest);
+        }
+
+        let burst = self.burst;
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        self.by_age.remove(&(bucket.updated, ip));
+        let elapsed = now.duration_since(bucket.updated).as_secs_f32();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(burst);
+        bucket.updated = now;
+        self.by_age.insert((now, ip));
+        bucket
+    }
+
+    /// Whether `ip` has a token to spend, or how many seconds until it will. Nothing is taken.
+    fn check(&mut self, ip: IpAddr, now: Instant) -> Result<(), u64> {
+        let per_second = self.per_second;
+        let bucket = self.refill(ip, now);
+        if bucket.tokens >= 1.0 {
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / per_second;
+            Err((wait.ceil() as u64).max(1))
+        }
+    }
+
+    /// Spends the token `check` found for `ip`.
+    fn take(&mut self, ip: IpAddr) {
+        if let Some(bucket) = self.buckets.get_mut(&ip) {
+            bucket.tokens -= 1.0;
+        }
+    }
+}
+
+type Connections = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Counts as one open connection for its client until dropped.
+#[derive(Default)]
+pub struct ConnectionGuard {
+    connection: Option<(Connections, IpAddr)>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some((connections, ip)) = &self.connection {
+            let mut connections = connections.lock().unwrap();
+            if let Some(count) = connections.get_mut(ip) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.remove(ip);
+                }
+            }
+        }
+    }
+}
+
+/// The open connections of every client, shared between whatever accepts connections and the `RateLimiter`
+/// that reads their requests.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    enabled: bool,
+    max_per_ip: usize,
+    trusted_proxies: Vec<IpCidr>,
+    connections: Connections,
+}
+
+impl ConnectionLimit {
+    /// Counts a connection as soon as it is accepted, so a client can't hold more than its share open while
+    /// being slow to send anything. Returns the seconds to wait if it already has as many as it may.
+    ///
+    /// A trusted proxy's connections belong to the clients behind it, each is counted by `RateLimiter::check`
+    /// once its request says whose it is.
+    pub fn accept(&self, peer: IpAddr) -> Result<ConnectionGuard, u64> {
+        if !self.enabled || proxy::is_trusted(&peer, &self.trusted_proxies) {
+            return Ok(ConnectionGuard::default());
+        }
+        let mut connections = self.connections.lock().unwrap();
+        self.count(&mut connections, peer)
+    }
+
+    fn count(&self, connections: &mut HashMap<IpAddr, usize>, ip: IpAddr) -> Result<ConnectionGuard, u64> {
+        let open = connections.get(&ip).copied().unwrap_or(0);
+        if open >= self.max_per_ip {
+            return Err(1);
+        }
+        connections.insert(ip, open + 1);
+        Ok(ConnectionGuard {
+            connection: Some((self.connections.clone(), ip)),
+        })
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    requests: Buckets,
+    api_writes: Buckets,
+    connections: ConnectionLimit,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, trusted_proxies: &[IpCidr]) -> Self {
+        Self {
+            config: config.clone(),
+            requests: Buckets::new(
+                config.requests_per_second,
+                config.request_burst,
+                config.max_tracked_clients,
+            ),
+            api_writes: Buckets::new(
+                config.api_writes_per_second,
+                config.api_write_burst,
+                config.max_tracked_clients,
+            ),
+            connections: ConnectionLimit {
+                enabled: config.enabled,
+                max_per_ip: config.max_connections_per_ip,
+                trusted_proxies: trusted_proxies.to_vec(),
+                connections: Connections::default(),
+            },
+        }
+    }
+
+    /// The per client connection count, for counting connections as they are accepted.
+    pub fn connection_limit(&self) -> ConnectionLimit {
+        self.connections.clone()
+    }
+
+    // CORS preflights aren't writes, counting them would take a second token for every cross origin POST.
+    fn is_api_write(request: &Request) -> bool {
+        request.path.starts_with("/api/") && !["GET", "HEAD", "OPTIONS"].contains(&request.method.as_str())
+    }
+
+    /// Lets the request through, or returns the number of seconds the client should wait before trying again.
+    /// `connection` is the guard from `ConnectionLimit::accept`, and is made to count against the client if the
+    /// request came through a proxy.
+    pub fn check(&mut self, request: &Request, connection: &mut ConnectionGuard) -> Result<(), u64> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+
+        let ip = request.client_ip();
+        // Held until the connection is counted, so two requests can't both take the last free slot.
+        let mut connections = self.connections.connections.lock().unwrap();
+        let counted = connection.connection.is_some();
+        if !counted && connections.get(&ip).copied().unwrap_or(0) >= self.config.max_connections_per_ip {
+            return Err(1);
+        }
+
+        // Both buckets must have a token before either is spent, a refused write shouldn't cost a request.
+        let now = Instant::now();
+        let is_api_write = Self::is_api_write(request);
+        self.requests.check(ip, now)?;
+        if is_api_write {
+            self.api_writes.check(ip, now)?;
+        }
+        self.requests.take(ip);
+        if is_api_write {
+            self.api_writes.take(ip);
+        }
+
+        if !counted {
+            *connection = self.connections.count(&mut connections, ip)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    fn request(method: &str, path: &str, client: u8) -> Request {
+        let mut request = Request::parse(format!("{} {} HTTP/1.1\r\n\r\n", method, path).as_bytes()).unwrap();
+        request.resolve_client_ip(&SocketAddr::new(ip(client), 50000), &[]);
+        request
+    }
+
+    fn limiter(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter::new(
+            &RateLimitConfig {
+                enabled: true,
+                ..config
+            },
+            &["10.0.0.0/24".parse().unwrap()],
+        )
+    }
+
+    /// Checks a request on a connection that hasn't been counted yet, as one from a proxy would be.
+    fn check(limiter: &mut RateLimiter, request: &Request) -> Result<ConnectionGuard, u64> {
+        let mut connection = ConnectionGuard::default();
+        limiter.check(request, &mut connection).map(|()| connection)
+    }
+
+    /// Spends `ip`'s tokens one at a time until refused.
+    fn drain(buckets: &mut Buckets, ip: IpAddr, now: Instant) -> usize {
+        let mut taken = 0;
+        while buckets.check(ip, now).is_ok() {
+            buckets.take(ip);
+            taken += 1;
+        }
+        taken
+    }
+
+    #[test]
+    fn allows_a_burst_then_refuses() {
+        let mut buckets = Buckets::new(1.0, 5, 10);
+        let now = Instant::now();
+        assert_eq!(drain(&mut buckets, ip(1), now), 5);
+        // Another client has a bucket of its own.
+        assert_eq!(drain(&mut buckets, ip(2), now), 5);
+    }
+
+    #[test]
+    fn refills_at_the_configured_rate_up_to_the_burst() {
+        let mut buckets = Buckets::new(2.0, 4, 10);
+        let now = Instant::now();
+        drain(&mut buckets, ip(1), now);
+
+        assert_eq!(drain(&mut buckets, ip(1), now + Duration::from_millis(500)), 1);
+        assert_eq!(drain(&mut buckets, ip(1), now + Duration::from_millis(1500)), 2);
+        assert_eq!(drain(&mut buckets, ip(1), now + Duration::from_secs(60)), 4);
+    }
+
+    #[test]
+    fn check_takes_nothing() {
+        let mut buckets = Buckets::new(1.0, 1, 10);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(buckets.check(ip(1), now), Ok(()));
+        }
+        buckets.take(ip(1));
+        assert!(buckets.check(ip(1), now).is_err());
+    }
+
+    #[test]
+    fn retry_after_is_the_seconds_until_the_next_token() {
+        let mut buckets = Buckets::new(0.25, 1, 10);
+        let now = Instant::now();
+        drain(&mut buckets, ip(1), now);
+        assert_eq!(buckets.check(ip(1), now), Err(4));
+        assert_eq!(buckets.check(ip(1), now + Duration::from_millis(1500)), Err(3));
+
+        // Never less than a second, even when the token is nearly there.
+        let mut buckets = Buckets::new(100.0, 1, 10);
+        drain(&mut buckets, ip(1), now);
+        assert_eq!(buckets.check(ip(1), now), Err(1));
+    }
+
+    #[test]
+    fn evicts_the_client_seen_least_recently() {
+        let mut buckets = Buckets::new(1.0, 2, 2);
+        let now = Instant::now();
+        drain(&mut buckets, ip(1), now);
+        buckets.check(ip(2), now + Duration::from_millis(100)).unwrap();
+        // Seeing ip(1) again makes ip(2) the stalest.
+        buckets.check(ip(1), now + Duration::from_millis(200)).unwrap_err();
+
+        buckets.check(ip(3), now + Duration::from_millis(300)).unwrap();
+        assert!(buckets.buckets.contains_key(&ip(1)));
+        assert!(!buckets.buckets.contains_key(&ip(2)));
+
+        buckets.check(ip(4), now + Duration::from_millis(400)).unwrap();
+        assert!(!buckets.buckets.contains_key(&ip(1)));
+        assert!(buckets.buckets.contains_key(&ip(3)));
+        assert_eq!(buckets.buckets.len(), 2);
+        let by_age: Vec<_> = buckets.by_age.iter().map(|(_, ip)| *ip).collect();
+        assert_eq!(by_age, [ip(3), ip(4)]);
+    }
+
+    #[test]
+    fn refused_api_write_costs_no_request_token() {
+        let mut limiter = limiter(RateLimitConfig {
+            requests_per_second: 0.001,
+            request_burst: 3,
+            api_writes_per_second: 0.001,
+            api_write_burst: 1,
+            ..RateLimitConfig::default()
+        });
+        assert!(check(&mut limiter, &request("POST", "/api/review_ratings", 1)).is_ok());
+        for _ in 0..5 {
+            assert!(check(&mut limiter, &request("POST", "/api/review_ratings", 1)).is_err());
+        }
+        assert!(check(&mut limiter, &request("GET", "/", 1)).is_ok());
+        assert!(check(&mut limiter, &request("GET", "/", 1)).is_ok());
+        assert!(check(&mut limiter, &request("GET", "/", 1)).is_err());
+    }
+
+    #[test]
+    fn preflights_are_not_writes() {
+        let mut limiter = limiter(RateLimitConfig {
+            api_writes_per_second: 0.001,
+            api_write_burst: 1,
+            ..RateLimitConfig::default()
+        });
+        for _ in 0..3 {
+            assert!(check(&mut limiter, &request("OPTIONS", "/api/review_ratings", 1)).is_ok());
+        }
+        assert!(check(&mut limiter, &request("POST", "/api/review_ratings", 1)).is_ok());
+    }
+
+    #[test]
+    fn connections_are_released_when_the_guard_drops() {
+        let mut limiter = limiter(RateLimitConfig {
+            max_connections_per_ip: 2,
+            ..RateLimitConfig::default()
+        });
+        let first = check(&mut limiter, &request("GET", "/", 1)).unwrap();
+        let second = check(&mut limiter, &request("GET", "/", 1)).unwrap();
+        assert_eq!(check(&mut limiter, &request("GET", "/", 1)).err(), Some(1));
+        assert!(check(&mut limiter, &request("GET", "/", 2)).is_ok());
+
+        drop(first);
+        let third = check(&mut limiter, &request("GET", "/", 1)).unwrap();
+        drop(second);
+        drop(third);
+        assert!(limiter.connections.connections.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn counts_connections_from_when_they_are_accepted() {
+        let mut limiter = limiter(RateLimitConfig {
+            max_connections_per_ip: 2,
+            ..RateLimitConfig::default()
+        });
+        let limit = limiter.connection_limit();
+        let mut first = limit.accept(ip(1)).unwrap();
+        let _second = limit.accept(ip(1)).unwrap();
+        assert_eq!(limit.accept(ip(1)).err(), Some(1));
+        assert!(limit.accept(ip(2)).is_ok());
+
+        // Already counted, so its request doesn't count it again.
+        assert!(limiter.check(&request("GET", "/", 1), &mut first).is_ok());
+        assert_eq!(limiter.connections.connections.lock().unwrap()[&ip(1)], 2);
+    }
+
+    #[test]
+    fn proxied_connections_count_against_the_client() {
+        let mut limiter = limiter(RateLimitConfig {
+            max_connections_per_ip: 1,
+            ..RateLimitConfig::default()
+        });
+        let proxy = SocketAddr::from(([10, 0, 0, 1], 40000));
+        let limit = limiter.connection_limit();
+        let mut connections: Vec<_> = (0..3).map(|_| limit.accept(proxy.ip()).unwrap()).collect();
+
+        let proxied = |client: u8| {
+            let mut request = request("GET", "/", 0);
+            request.headers.push(format!("X-Forwarded-For: {}", ip(client)));
+            request.resolve_client_ip(&proxy, &["10.0.0.0/24".parse().unwrap()]);
+            request
+        };
+        assert!(limiter.check(&proxied(1), &mut connections[0]).is_ok());
+        assert_eq!(limiter.check(&proxied(1), &mut connections[1]), Err(1));
+        assert!(limiter.check(&proxied(2), &mut connections[2]).is_ok());
+        assert!(!limiter.connections.connections.lock().unwrap().contains_key(&proxy.ip()));
+    }
+
+    #[test]
+    fn disabled_lets_everything_through() {
+        let mut limiter = RateLimiter::new(
+            &RateLimitConfig {
+                enabled: false,
+                request_burst: 1,
+                max_connections_per_ip: 1,
+                ..RateLimitConfig::default()
+            },
+            &[],
+        );
+        let limit = limiter.connection_limit();
+        let guards: Vec<_> = (0..10).map(|_| limit.accept(ip(1)).unwrap()).collect();
+        assert_eq!(guards.len(), 10);
+        let guards: Vec<_> = (0..10).map(|_| check(&mut limiter, &request("GET", "/", 1)).unwrap()).collect();
+        assert_eq!(guards.len(), 10);
+    }
+}
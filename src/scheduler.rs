@@ -0,0 +1,317 @@
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex},
+    time::Instant,
+};
+
+use crate::config::SchedulerConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobClass {
+    /// The index page, the first thing a visitor waits on.
+    Html,
+    Api,
+    /// Everything the page pulls in that isn't large enough to count as media.
+    Asset,
+    /// Large downloads such as `/video.webm` that tie a worker up for a long time.
+    Media,
+}
+
+impl JobClass {
+    pub const ALL: [JobClass; 4] = [JobClass::Html, JobClass::Api, JobClass::Asset, JobClass::Media];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobClass::Html => "html",
+            JobClass::Api => "api",
+            JobClass::Asset => "asset",
+            JobClass::Media => "media",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+struct ClassQueue<T> {
+    jobs: VecDeque<(Instant, T)>,
+    in_flight: usize,
+}
+
+struct SchedulerState<T> {
+    classes: [ClassQueue<T>; 4],
+    closed: bool,
+    // Shared workers that should stop the next time they come looking for a job.
+    retiring: usize,
+}
+
+/// Hands jobs to workers by weighted priority. A job's priority grows the longer it waits so low weight
+/// classes are never starved, and each class can be capped to a number of workers at once.
+pub struct Scheduler<T> {
+    state: Mutex<SchedulerState<T>>,
+    available: Condvar,
+    config: SchedulerConfig,
+    max_size: usize,
+}
+
+impl<T> Scheduler<T> {
+    pub fn new(config: &SchedulerConfig, max_size: usize) -> Self {
+        Self {
+            state: Mutex::new(SchedulerState {
+                classes: std::array::from_fn(|_| ClassQueue {
+                    jobs: VecDeque::new(),
+                    in_flight: 0,
+                }),
+                closed: false,
+                retiring: 0,
+            }),
+            available: Condvar::new(),
+            config: config.clone(),
+            max_size,
+        }
+    }
+
+    /// Queues a job, handing it back if its class is full or the scheduler is closed.
+    pub fn push(&self, class: JobClass, job: T) -> Result<(), T> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(job);
+        }
+        let queue = &mut state.classes[class.index()];
+        if queue.jobs.len() >= self.max_size {
+            return Err(job);
+        }
+        queue.jobs.push_back((Instant::now(), job));
+        drop(state);
+        // Reserved workers only take their own class, so wake everyone and let them sort it out.
+        self.available.notify_all();
+        Ok(())
+    }
+
+    fn pick(&self, state: &SchedulerState<T>, reserved: Option<JobClass>, now: Instant) -> Option<JobClass> {
+        let mut best: Option<(JobClass, f32)> = None;
+        for class in JobClass::ALL {
+            if reserved.is_some_and(|reserved| reserved != class) {
+                continue;
+            }
+            let queue = &state.classes[class.index()];
+            let Some((queued_at, _)) = queue.jobs.front() else {
+                continue;
+            };
+            let class_config = self.config.class(class);
+            if class_config
+                .max_concurrent
+                .is_some_and(|max_concurrent| queue.in_flight >= max_concurrent)
+            {
+                continue;
+            }
+
+            let waited = now.duration_since(*queued_at).as_millis() as f32;
+            let score = class_config.weight as f32 * (1.0 + waited / self.config.aging_ms as f32);
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((class, score));
+            }
+        }
+        best.map(|(class, _)| class)
+    }
+
+    /// Waits for the next job this worker is allowed to run. Returns `None` once the scheduler is closed or
+    /// when a shared worker has been retired. Every job handed out must be followed by a call to `finish`.
+    pub fn pop(&self, reserved: Option<JobClass>) -> Option<(JobClass, T)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if reserved.is_none() && state.retiring > 0 {
+                state.retiring -= 1;
+                return None;
+            }
+            if let Some(class) = self.pick(&state, reserved, Instant::now()) {
+                let queue = &mut state.classes[class.index()];
+                if let Some((_, job)) = queue.jobs.pop_front() {
+                    queue.in_flight += 1;
+                    return Some((class, job));
+                }
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    pub fn finish(&self, class: JobClass) {
+        let mut state = self.state.lock().unwrap();
+        let queue = &mut state.classes[class.index()];
+        queue.in_flight = queue.in_flight.saturating_sub(1);
+        drop(state);
+        // A worker may have been waiting for this class to drop under its limit.
+        self.available.notify_all();
+    }
+
+    /// Stops one shared worker, whichever next finds itself waiting for a job.
+    pub fn retire_worker(&self) {
+        self.state.lock().unwrap().retiring += 1;
+        self.available.notify_all();
+    }
+
+    /// Wakes every worker so they can shut down, handing back every job still queued. Jobs already handed
+    /// out keep running.
+    pub fn close(&self) -> Vec<T> {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        let jobs = state
+            .classes
+            .iter_mut()
+            .flat_map(|queue| queue.jobs.drain(..).map(|(_, job)| job))
+            .collect();
+        drop(state);
+        self.available.notify_all();
+        jobs
+    }
+
+    pub fn len(&self, class: JobClass) -> usize {
+        self.state.lock().unwrap().classes[class.index()].jobs.len()
+    }
+
+    /// Queued jobs a worker could start right now, leaving out any held back by their class's
+    /// `max_concurrent`.
+    pub fn runnable_len(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        JobClass::ALL
+            .iter()
+            .map(|class| {
+                let queue = &state.classes[class.index()];
+                match self.config.class(*class).max_concurrent {
+                    Some(max_concurrent) => queue.jobs.len().min(max_concurrent.saturating_sub(queue.in_flight)),
+                    None => queue.jobs.len(),
+                }
+            })
+            .sum()
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn scheduler(aging_ms: u64) -> Scheduler<usize> {
+        Scheduler::new(
+            &SchedulerConfig {
+                aging_ms,
+                ..SchedulerConfig::default()
+            },
+            10,
+        )
+    }
+
+    /// Makes the jobs queued for `class` look like they have been waiting `by` longer than they have.
+    fn backdate(scheduler: &Scheduler<usize>, class: JobClass, by: Duration) {
+        let mut state = scheduler.state.lock().unwrap();
+        for (queued_at, _) in &mut state.classes[class.index()].jobs {
+            *queued_at -= by;
+        }
+    }
+
+    #[test]
+    fn higher_weight_goes_first() {
+        let scheduler = scheduler(60_000);
+        for (job, class) in [JobClass::Media, JobClass::Asset, JobClass::Api, JobClass::Html].into_iter().enumerate() {
+            scheduler.push(class, job).unwrap();
+        }
+        let order: Vec<_> = (0..4).map(|_| scheduler.pop(None).unwrap()).collect();
+        assert_eq!(
+            order,
+            [(JobClass::Html, 3), (JobClass::Api, 2), (JobClass::Asset, 1), (JobClass::Media, 0)]
+        );
+    }
+
+    #[test]
+    fn waiting_long_enough_beats_a_higher_weight() {
+        // Media has weight 1 against html's 8, so it needs to have waited 7 aging periods longer.
+        let scheduler = scheduler(10);
+        scheduler.push(JobClass::Media, 0).unwrap();
+        scheduler.push(JobClass::Html, 1).unwrap();
+        backdate(&scheduler, JobClass::Media, Duration::from_millis(50));
+        assert_eq!(scheduler.pop(None), Some((JobClass::Html, 1)));
+
+        scheduler.push(JobClass::Html, 2).unwrap();
+        backdate(&scheduler, JobClass::Media, Duration::from_millis(100));
+        assert_eq!(scheduler.pop(None), Some((JobClass::Media, 0)));
+    }
+
+    #[test]
+    fn reserved_workers_only_take_their_own_class() {
+        let scheduler = scheduler(60_000);
+        scheduler.push(JobClass::Asset, 0).unwrap();
+        let now = Instant::now();
+        assert_eq!(scheduler.pick(&scheduler.state.lock().unwrap(), Some(JobClass::Html), now), None);
+
+        scheduler.push(JobClass::Html, 1).unwrap();
+        assert_eq!(scheduler.pop(Some(JobClass::Html)), Some((JobClass::Html, 1)));
+        assert_eq!(scheduler.pop(None), Some((JobClass::Asset, 0)));
+    }
+
+    #[test]
+    fn retiring_stops_exactly_one_shared_worker() {
+        let scheduler = scheduler(60_000);
+        scheduler.push(JobClass::Html, 0).unwrap();
+        scheduler.retire_worker();
+
+        // Reserved workers are never retired.
+        assert_eq!(scheduler.pop(Some(JobClass::Html)), Some((JobClass::Html, 0)));
+        scheduler.push(JobClass::Asset, 1).unwrap();
+        assert_eq!(scheduler.pop(None), None);
+        assert_eq!(scheduler.pop(None), Some((JobClass::Asset, 1)));
+    }
+
+    #[test]
+    fn close_hands_back_queued_jobs_and_wakes_workers() {
+        let scheduler = Arc::new(scheduler(60_000));
+        let waiting = std::thread::spawn({
+            let scheduler = scheduler.clone();
+            move || scheduler.pop(Some(JobClass::Media))
+        });
+        scheduler.push(JobClass::Html, 0).unwrap();
+        scheduler.push(JobClass::Asset, 1).unwrap();
+        scheduler.push(JobClass::Asset, 2).unwrap();
+        assert_eq!(scheduler.pop(None), Some((JobClass::Html, 0)));
+
+        let mut jobs = scheduler.close();
+        jobs.sort();
+        assert_eq!(jobs, [1, 2]);
+        assert_eq!(waiting.join().unwrap(), None);
+        assert_eq!(scheduler.pop(None), None);
+        assert_eq!(scheduler.push(JobClass::Html, 3), Err(3));
+    }
+
+    #[test]
+    fn jobs_over_their_class_limit_are_not_runnable() {
+        let mut config = SchedulerConfig::default();
+        config.media.max_concurrent = Some(1);
+        config.asset.max_concurrent = None;
+        let scheduler = Scheduler::new(&config, 10);
+
+        for job in 0..3 {
+            scheduler.push(JobClass::Media, job).unwrap();
+        }
+        assert_eq!(scheduler.runnable_len(), 1);
+        let (class, _) = scheduler.pop(None).unwrap();
+        assert_eq!(class, JobClass::Media);
+        assert_eq!(scheduler.len(JobClass::Media), 2);
+        assert_eq!(scheduler.runnable_len(), 0);
+
+        scheduler.push(JobClass::Asset, 3).unwrap();
+        scheduler.push(JobClass::Asset, 4).unwrap();
+        assert_eq!(scheduler.runnable_len(), 2);
+
+        scheduler.finish(JobClass::Media);
+        assert_eq!(scheduler.runnable_len(), 3);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::config::SecurityHeadersConfig;
+use crate::http_utils::{Request, Response};
+use crate::middleware::Middleware;
+
+/// Adds the configured security headers to responses. Headers a route already set itself are left alone.
+pub struct SecurityHeaders {
+    config: SecurityHeadersConfig,
+    headers: Vec<(String, String)>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &SecurityHeadersConfig) -> Self {
+        let mut headers = vec![];
+        let mut content_security_policy = config.content_security_policy.clone().unwrap_or_default();
+        if let Some(frame_ancestors) = &config.frame_ancestors {
+            if !content_security_policy.is_empty() {
+                content_security_policy.push_str("; ");
+            }
+            content_security_policy.push_str(&format!("frame-ancestors {}", frame_ancestors));
+            // The 3DS browser and other old ones only know the header CSP replaced.
+            match frame_ancestors.as_str() {
+                "'none'" => headers.push(("X-Frame-Options".to_string(), "DENY".to_string())),
+                "'self'" => headers.push(("X-Frame-Options".to_string(), "SAMEORIGIN".to_string())),
+                _ => {}
+            }
+        }
+        if !content_security_policy.is_empty() {
+            headers.push(("Content-Security-Policy".to_string(), content_security_policy));
+        }
+        if config.content_type_options {
+            headers.push(("X-Content-Type-Options".to_string(), "nosniff".to_string()));
+        }
+        for (name, value) in [
+            ("Referrer-Policy", &config.referrer_policy),
+            ("Permissions-Policy", &config.permissions_policy),
+            ("Strict-Transport-Security", &config.strict_transport_security),
+        ] {
+            if let Some(value) = value {
+                headers.push((name.to_string(), value.clone()));
+            }
+        }
+
+        Self {
+            config: config.clone(),
+            headers,
+        }
+    }
+
+    fn opted_out(&self, path: &str) -> bool {
+        self.config.skip_routes.iter().any(|route| match route.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == route,
+        })
+    }
+
+    /// `path` is `None` for responses to requests that couldn't be parsed, which always get the headers.
+    pub fn apply(&self, path: Option<&str>, response: &mut Response) {
+        if !self.config.enabled || path.is_some_and(|path| self.opted_out(path)) {
+            return;
+        }
+
+        // The most specific content type prefix wins.
+        let overrides = self
+            .config
+            .content_types
+            .iter()
+            .filter(|(content_type, _)| response.content_type.starts_with(content_type.as_str()))
+            .max_by_key(|(content_type, _)| content_type.len())
+            .map(|(_, overrides)| overrides);
+
+        let mut headers = self.headers.clone();
+        if let Some(overrides) = overrides {
+            for (name, value) in overrides {
+                headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+                // An empty value drops the header for this content type.
+                if !value.is_empty() {
+                    headers.push((name.clone(), value.clone()));
+                }
+            }
+        }
+
+        for (name, value) in headers {
+            if !response.has_header(&name) {
+                response.headers.push(format!("{}: {}", name, value));
+            }
+        }
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn after(&self, request: &Request, response: &mut Response) {
+        self.apply(Some(&request.path), response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_utils::content_types;
+
+    fn headers(config: &SecurityHeadersConfig, path: Option<&str>, content_type: &'static str) -> Vec<String> {
+        let mut response = Response::new();
+        response.content_type = content_type;
+        SecurityHeaders::new(config).apply(path, &mut response);
+        response.headers
+    }
+
+    #[test]
+    fn default_set() {
+        assert_eq!(
+            headers(&SecurityHeadersConfig::default(), Some("/"), content_types::HTML),
+            [
+                "X-Frame-Options: DENY",
+                "Content-Security-Policy: default-src 'self'; img-src 'self' data:; style-src 'self' 'unsafe-inline'; \
+                 object-src 'none'; base-uri 'self'; frame-ancestors 'none'",
+                "X-Content-Type-Options: nosniff",
+                "Referrer-Policy: strict-origin-when-cross-origin",
+                "Permissions-Policy: camera=(), microphone=(), geolocation=(), payment=()",
+            ]
+        );
+    }
+
+    #[test]
+    fn json_gets_its_own_policy() {
+        let headers = headers(&SecurityHeadersConfig::default(), Some("/api/visits"), content_types::JSON);
+        assert!(headers.contains(&"Content-Security-Policy: default-src 'none'; frame-ancestors 'none'".to_string()));
+        assert_eq!(headers.iter().filter(|header| header.starts_with("Content-Security-Policy")).count(), 1);
+        assert!(headers.contains(&"X-Content-Type-Options: nosniff".to_string()));
+    }
+
+    #[test]
+    fn frame_ancestors() {
+        let config = SecurityHeadersConfig {
+            content_security_policy: None,
+            frame_ancestors: Some("'self'".to_string()),
+            ..SecurityHeadersConfig::default()
+        };
+        let sent = headers(&config, Some("/"), content_types::HTML);
+        assert!(sent.contains(&"X-Frame-Options: SAMEORIGIN".to_string()));
+        assert!(sent.contains(&"Content-Security-Policy: frame-ancestors 'self'".to_string()));
+
+        // Only CSP can say which other origins may frame the page.
+        let config = SecurityHeadersConfig {
+            content_security_policy: None,
+            frame_ancestors: Some("https://a.example".to_string()),
+            ..SecurityHeadersConfig::default()
+        };
+        let sent = headers(&config, Some("/"), content_types::HTML);
+        assert!(!sent.iter().any(|header| header.starts_with("X-Frame-Options")));
+        assert!(sent.contains(&"Content-Security-Policy: frame-ancestors https://a.example".to_string()));
+
+        let config = SecurityHeadersConfig {
+            content_security_policy: None,
+            frame_ancestors: None,
+            ..SecurityHeadersConfig::default()
+        };
+        let sent = headers(&config, Some("/"), content_types::HTML);
+        assert!(!sent.iter().any(|header| header.starts_with("X-Frame-Options")));
+        assert!(!sent.iter().any(|header| header.starts_with("Content-Security-Policy")));
+    }
+
+    #[test]
+    fn hsts_only_when_configured() {
+        assert!(
+            !headers(&SecurityHeadersConfig::default(), Some("/"), content_types::HTML)
+                .iter()
+                .any(|header| header.starts_with("Strict-Transport-Security"))
+        );
+        let config = SecurityHeadersConfig {
+            strict_transport_security: Some("max-age=31536000".to_string()),
+            ..SecurityHeadersConfig::default()
+        };
+        assert!(
+            headers(&config, Some("/"), content_types::HTML)
+                .contains(&"Strict-Transport-Security: max-age=31536000".to_string())
+        );
+    }
+
+    #[test]
+    fn most_specific_content_type_override_wins() {
+        let config = SecurityHeadersConfig {
+            content_types: [
+                ("image/".to_string(), [("Referrer-Policy".to_string(), "no-referrer".to_string())].into()),
+                ("image/png".to_string(), [("Permissions-Policy".to_string(), String::new())].into()),
+            ]
+            .into(),
+            ..SecurityHeadersConfig::default()
+        };
+        let png = headers(&config, Some("/a.png"), content_types::PNG);
+        assert!(png.contains(&"Referrer-Policy: strict-origin-when-cross-origin".to_string()));
+        assert!(!png.iter().any(|header| header.starts_with("Permissions-Policy")));
+
+        let jpeg = headers(&config, Some("/a.jpg"), "image/jpeg");
+        assert!(jpeg.contains(&"Referrer-Policy: no-referrer".to_string()));
+        assert!(jpeg.iter().any(|header| header.starts_with("Permissions-Policy")));
+    }
+
+    #[test]
+    fn headers_set_by_the_route_are_kept() {
+        let mut response = Response::new();
+        response.content_type = content_types::HTML;
+        response.headers.push("referrer-policy: no-referrer".to_string());
+        SecurityHeaders::new(&SecurityHeadersConfig::default()).apply(Some("/"), &mut response);
+        let referrer_policies = response
+            .headers
+            .iter()
+            .filter(|header| header.to_lowercase().starts_with("referrer-policy"));
+        assert_eq!(referrer_policies.count(), 1);
+        assert!(response.headers.contains(&"referrer-policy: no-referrer".to_string()));
+    }
+
+    #[test]
+    fn skipped_routes_and_disabled_get_nothing() {
+        let config = SecurityHeadersConfig {
+            skip_routes: vec!["/metrics".to_string(), "/books/*".to_string()],
+            ..SecurityHeadersConfig::default()
+        };
+        assert!(headers(&config, Some("/metrics"), content_types::PLAIN).is_empty());
+        assert!(headers(&config, Some("/books/HNI_0002.jpg"), content_types::PNG).is_empty());
+        assert!(!headers(&config, Some("/metrics/extra"), content_types::PLAIN).is_empty());
+        // Unparsed requests have no path to skip by.
+        assert!(!headers(&config, None, content_types::PLAIN).is_empty());
+
+        let config = SecurityHeadersConfig {
+            enabled: false,
+            ..SecurityHeadersConfig::default()
+        };
+        assert!(headers(&config, None, content_types::HTML).is_empty());
+    }
+}
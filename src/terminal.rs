@@ -0,0 +1,96 @@
+//! The front end for running on a desktop: the terminal stands in for both screens and typed commands for
+//! the buttons.
+
+use std::io::BufRead;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use site_3ds::panics;
+use site_3ds::platform::{self, Input, Platform, Screen};
+
+/// How long a frame is on the 3DS, where the main loop waits for vblank.
+const FRAME: Duration = Duration::from_micros(16_667);
+
+struct Terminal {
+    commands: Receiver<Input>,
+    /// Cleared by Ctrl-C or SIGTERM.
+    running: Arc<AtomicBool>,
+}
+
+impl Terminal {
+    /// Reads commands typed into the terminal on a thread of their own, so the main loop never waits on them.
+    /// Closing stdin (or never having one) leaves the server running, there is just no way to stop it cleanly.
+    fn new() -> Self {
+        let (sender, commands) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                let input = match line.trim() {
+                    "l" | "log" => Input::CycleLogLevel,
+                    "q" | "quit" | "exit" => Input::Exit,
+                    "" => continue,
+                    other => {
+                        println!("Unknown command {:?}", other);
+                        continue;
+                    }
+                };
+                if sender.send(input).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // The first signal shuts down as `q` would, a second gives up waiting on the shutdown.
+        let running = Arc::new(AtomicBool::new(true));
+        let handler = {
+            let running = running.clone();
+            move || {
+                if !running.swap(false, Ordering::Relaxed) {
+                    std::process::exit(130);
+                }
+            }
+        };
+        if let Err(e) = ctrlc::set_handler(handler) {
+            println!("Couldn't handle Ctrl-C, enter q to stop cleanly: {e}");
+        }
+
+        Self { commands, running }
+    }
+}
+
+impl Platform for Terminal {
+    fn is_running(&mut self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    fn inputs(&mut self) -> Vec<Input> {
+        self.commands.try_iter().collect()
+    }
+
+    fn tick(&mut self) {
+        std::thread::sleep(FRAME);
+    }
+
+    fn show(&mut self, _screen: Screen) {}
+
+    fn host_address(&self) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    }
+
+    fn prompt(&self, input: Input) -> &'static str {
+        match input {
+            Input::CycleLogLevel => "Enter l",
+            Input::Exit => "Enter q",
+        }
+    }
+}
+
+pub fn main() {
+    panics::install_hook();
+    platform::run(&mut Terminal::new());
+}
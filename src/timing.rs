@@ -0,0 +1,150 @@
+use std::{
+    cell::Cell,
+    fmt::Write,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::database::{self, Database};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting in the scheduler for a worker.
+    Queue,
+    /// PROXY header, request line and headers, and working out the client address.
+    Parse,
+    /// Building the response, including any time spent waiting on the database.
+    Route,
+    /// Waiting to lock the database.
+    DbLock,
+    /// Writing the response to the socket.
+    Send,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 5] = [Phase::Queue, Phase::Parse, Phase::Route, Phase::DbLock, Phase::Send];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Queue => "queue",
+            Phase::Parse => "parse",
+            Phase::Route => "route",
+            Phase::DbLock => "db_lock",
+            Phase::Send => "send",
+        }
+    }
+}
+
+/// How long one request spent in each phase, phases it never went through are left out.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timings {
+    phases: [Option<Duration>; Phase::ALL.len()],
+}
+
+impl Timings {
+    pub fn set(&mut self, phase: Phase, duration: Duration) {
+        self.phases[phase as usize] = Some(duration);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Phase, Duration)> + '_ {
+        Phase::ALL
+            .iter()
+            .filter_map(|phase| self.phases[*phase as usize].map(|duration| (*phase, duration)))
+    }
+
+    /// A `Server-Timing` header for everything recorded so far, so it has to be built before sending.
+    pub fn header(&self) -> String {
+        let mut header = String::from("Server-Timing: ");
+        for (i, (phase, duration)) in self.iter().enumerate() {
+            if i > 0 {
+                header.push_str(", ");
+            }
+            let _ = write!(header, "{};dur={:.3}", phase.name(), duration.as_secs_f64() * 1000.0);
+        }
+        header
+    }
+}
+
+thread_local! {
+    // Lock waits add up here as routing goes, the worker (or event loop) takes the total afterwards.
+    static DB_LOCK_WAIT: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+/// Locks the database the same as `database::lock`, counting the wait towards this request's `db_lock` time.
+pub fn timed_lock(db: &Mutex<Database>) -> MutexGuard<'_, Database> {
+    let started = Instant::now();
+    let guard = database::lock(db);
+    DB_LOCK_WAIT.with(|wait| wait.set(wait.get() + started.elapsed()));
+    guard
+}
+
+/// The lock wait since the last call, resetting it for the next request.
+pub fn take_db_lock_wait() -> Duration {
+    DB_LOCK_WAIT.with(|wait| wait.replace(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::config::DatabaseConfig;
+
+    #[test]
+    fn header_lists_phases_in_order_in_milliseconds() {
+        let mut timings = Timings::default();
+        timings.set(Phase::Send, Duration::from_micros(2_500));
+        timings.set(Phase::Queue, Duration::from_micros(1_234_567));
+        timings.set(Phase::Parse, Duration::from_nanos(400));
+        timings.set(Phase::Route, Duration::ZERO);
+        assert_eq!(
+            timings.header(),
+            "Server-Timing: queue;dur=1234.567, parse;dur=0.000, route;dur=0.000, send;dur=2.500"
+        );
+    }
+
+    #[test]
+    fn header_leaves_out_phases_never_reached() {
+        let mut timings = Timings::default();
+        timings.set(Phase::Parse, Duration::from_millis(3));
+        assert_eq!(timings.header(), "Server-Timing: parse;dur=3.000");
+        assert_eq!(timings.iter().collect::<Vec<_>>(), [(Phase::Parse, Duration::from_millis(3))]);
+    }
+
+    #[test]
+    fn setting_a_phase_again_replaces_it() {
+        let mut timings = Timings::default();
+        timings.set(Phase::DbLock, Duration::from_millis(1));
+        timings.set(Phase::DbLock, Duration::from_millis(7));
+        assert_eq!(timings.header(), "Server-Timing: db_lock;dur=7.000");
+    }
+
+    #[test]
+    fn lock_waits_add_up_until_taken() {
+        let filename = std::env::temp_dir().join(format!("site_3ds_timing_{}.bin", std::process::id()));
+        let db = Arc::new(Mutex::new(Database::new(&DatabaseConfig {
+            filename: filename.to_string_lossy().into_owned(),
+            ..DatabaseConfig::default()
+        })));
+        take_db_lock_wait();
+
+        let held = db.lock().unwrap();
+        let waiter = std::thread::spawn({
+            let db = db.clone();
+            move || {
+                drop(timed_lock(&db));
+                drop(timed_lock(&db));
+                (take_db_lock_wait(), take_db_lock_wait())
+            }
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        drop(held);
+
+        let (waited, after) = waiter.join().unwrap();
+        assert!(waited >= Duration::from_millis(40), "{:?}", waited);
+        assert_eq!(after, Duration::ZERO);
+        // Each thread counts its own waits.
+        assert_eq!(take_db_lock_wait(), Duration::ZERO);
+        let _ = std::fs::remove_file(filename);
+    }
+}
@@ -0,0 +1,452 @@
+//! Runs the server on a loopback port and talks to it over plain TCP, the way a browser would.
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use site_3ds::config::{Config, ServerMode};
+use site_3ds::database::Database;
+use site_3ds::event_loop::EventLoop;
+use site_3ds::handler::{Handler, SERVE_REQUESTS, ServeRequest, Server};
+use socket2::{Domain, Socket, Type};
+
+const FRAME: Duration = Duration::from_millis(1);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server stepped on its own thread, as the main loop would, until it is dropped.
+struct TestServer {
+    addr: SocketAddr,
+    keep_running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    fn start(mode: ServerMode) -> Self {
+        Self::start_with(mode, |_| {})
+    }
+
+    /// Starts a server with the test defaults, then whatever `configure` changes on top of them.
+    fn start_with(mode: ServerMode, configure: impl FnOnce(&mut Config)) -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let mut config = Config::default();
+        config.server.mode = mode;
+        config.server.port = 0;
+        config.rate_limit.enabled = false;
+        config.access_log.enabled = false;
+        config.database.filename = std::env::temp_dir()
+            .join(format!(
+                "site_3ds_test_{}_{}.bin",
+                std::process::id(),
+                NEXT_ID.fetch_add(1, Ordering::Relaxed)
+            ))
+            .to_string_lossy()
+            .into_owned();
+        configure(&mut config);
+
+        let keep_running = Arc::new(AtomicBool::new(true));
+        let (addr_sender, addr_receiver) = mpsc::channel();
+        let thread = thread::spawn({
+            let keep_running = keep_running.clone();
+            move || {
+                let db = Arc::new(Mutex::new(Database::new(&config.database)));
+                let mut server: Box<dyn Server> = match config.server.mode {
+                    ServerMode::Threaded => Box::new(Handler::new(db, &config)),
+                    ServerMode::EventLoop => Box::new(EventLoop::new(db, &config)),
+                };
+                addr_sender.send(server.local_addr()).unwrap();
+                while keep_running.load(Ordering::Relaxed) {
+                    server.step();
+                    thread::sleep(FRAME);
+                }
+                server.shutdown(Duration::from_secs(1));
+            }
+        });
+
+        let port = addr_receiver.recv().unwrap().port();
+        Self {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            keep_running,
+            thread: Some(thread),
+        }
+    }
+
+    fn get(&self, path: &str, headers: &[&str]) -> Response {
+        self.request("GET", path, headers, "")
+    }
+
+    fn request(&self, method: &str, path: &str, headers: &[&str], body: &str) -> Response {
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path);
+        for header in headers {
+            request.push_str(header);
+            request.push_str("\r\n");
+        }
+        if !body.is_empty() {
+            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+
+        let mut stream = TcpStream::connect(self.addr).unwrap();
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        Response::read(&mut stream, method != "HEAD")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.keep_running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    /// Reads up to the `Content-Length` the server sent, so it doesn't matter whether it closes the connection.
+    /// A response to `HEAD` has the length of the body it would have sent but no body.
+    fn read(stream: &mut TcpStream, has_body: bool) -> Self {
+        let mut data = vec![];
+        let mut buffer = [0; 4096];
+        let head_end = loop {
+            if let Some(position) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+                break position;
+            }
+            let read = stream.read(&mut buffer).unwrap();
+            assert_ne!(read, 0, "connection closed before the end of the head");
+            data.extend_from_slice(&buffer[..read]);
+        };
+
+        let head = String::from_utf8(data[..head_end].to_vec()).unwrap();
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(':').unwrap();
+                (name.to_string(), value.trim().to_string())
+            })
+            .collect();
+        let mut response = Self {
+            status,
+            headers,
+            body: data[head_end + 4..].to_vec(),
+        };
+
+        let length = match response.header("Content-Length") {
+            Some(length) if has_body => length.parse().unwrap(),
+            _ => 0,
+        };
+        while response.body.len() < length {
+            let read = stream.read(&mut buffer).unwrap();
+            assert_ne!(read, 0, "connection closed before the end of the body");
+            response.body.extend_from_slice(&buffer[..read]);
+        }
+        response
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+/// Connects from another loopback address, so the server sees a different client.
+fn connect_from(source: Ipv4Addr, addr: SocketAddr) -> TcpStream {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    socket.bind(&SocketAddr::from((source, 0)).into()).unwrap();
+    socket.connect(&addr.into()).unwrap();
+    socket.into()
+}
+
+fn for_each_mode(test: impl Fn(&TestServer)) {
+    for mode in [ServerMode::Threaded, ServerMode::EventLoop] {
+        test(&TestServer::start(mode));
+    }
+}
+
+/// The biggest asset in the build, for ranges to have something to cut up.
+fn largest_asset() -> &'static ServeRequest {
+    SERVE_REQUESTS.iter().max_by_key(|asset| asset.body.len()).unwrap()
+}
+
+#[test]
+fn serves_every_asset() {
+    for_each_mode(|server| {
+        let index = server.get("/", &[]);
+        assert_eq!(index.status, 200);
+        assert_eq!(index.body, SERVE_REQUESTS[0].body);
+
+        for asset in SERVE_REQUESTS.iter() {
+            let response = server.get(asset.path, &[]);
+            assert_eq!(response.status, 200, "{}", asset.path);
+            assert_eq!(response.header("Content-Type"), Some(asset.content_type), "{}", asset.path);
+            assert_eq!(response.header("Content-Encoding"), None, "{}", asset.path);
+            assert_eq!(response.body, asset.body, "{}", asset.path);
+        }
+    });
+}
+
+#[test]
+fn head_has_length_but_no_body() {
+    for_each_mode(|server| {
+        let asset = &SERVE_REQUESTS[0];
+        let response = server.request("HEAD", asset.path, &[], "");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Length"), Some(asset.body.len().to_string().as_str()));
+    });
+}
+
+#[test]
+fn unknown_paths_are_not_found() {
+    for_each_mode(|server| {
+        let page = server.get("/no/such/page", &[]);
+        assert_eq!(page.status, 404);
+        assert_eq!(page.header("Content-Type"), Some("text/html"));
+
+        let api = server.get("/api/no_such_route", &[]);
+        assert_eq!(api.status, 404);
+        assert_eq!(api.json()["error"]["code"], "not_found");
+    });
+}
+
+#[test]
+fn compresses_for_clients_that_accept_it() {
+    for_each_mode(|server| {
+        for asset in SERVE_REQUESTS.iter() {
+            let encodings = [
+                ("gzip", asset.body_gzip),
+                ("deflate", asset.body_deflate),
+                ("br", asset.body_br),
+                ("zstd", asset.body_zstd),
+            ];
+            for (coding, encoded) in encodings {
+                let Some(encoded) = encoded.filter(|encoded| encoded.len() < asset.body.len()) else {
+                    continue;
+                };
+                let response = server.get(asset.path, &[&format!("Accept-Encoding: {}", coding)]);
+                assert_eq!(response.status, 200);
+                assert_eq!(response.header("Content-Encoding"), Some(coding), "{}", asset.path);
+                assert_eq!(response.body, encoded, "{} {}", asset.path, coding);
+
+                let refused = server.get(asset.path, &[&format!("Accept-Encoding: {};q=0", coding)]);
+                assert_eq!(refused.header("Content-Encoding"), None, "{}", asset.path);
+                assert_eq!(refused.body, asset.body, "{}", asset.path);
+            }
+        }
+    });
+}
+
+#[test]
+fn serves_ranges() {
+    for_each_mode(|server| {
+        let asset = largest_asset();
+        let len = asset.body.len();
+        assert!(len >= 2, "no asset big enough to take a range of");
+        let middle = len / 2;
+
+        let response = server.get(asset.path, &[&format!("Range: bytes=1-{}", middle)]);
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("Content-Range"), Some(format!("bytes 1-{}/{}", middle, len).as_str()));
+        assert_eq!(response.body, &asset.body[1..=middle]);
+
+        let suffix = server.get(asset.path, &["Range: bytes=-1"]);
+        assert_eq!(suffix.status, 206);
+        assert_eq!(suffix.body, &asset.body[len - 1..]);
+
+        let open = server.get(asset.path, &[&format!("Range: bytes={}-", middle)]);
+        assert_eq!(open.status, 206);
+        assert_eq!(open.body, &asset.body[middle..]);
+
+        let past_end = server.get(asset.path, &[&format!("Range: bytes={}-", len)]);
+        assert_eq!(past_end.status, 416);
+        assert_eq!(past_end.header("Content-Range"), Some(format!("bytes */{}", len).as_str()));
+        assert!(past_end.body.is_empty());
+
+        let ignored = server.get(asset.path, &["Range: bytes=0-1,3-4"]);
+        assert_eq!(ignored.status, 200);
+        assert_eq!(ignored.body, asset.body);
+    });
+}
+
+#[test]
+fn records_review_ratings() {
+    for_each_mode(|server| {
+        let json = ["Content-Type: application/json"];
+        let rated = server.request("POST", "/api/review_ratings", &json, r#"{"id": 7, "positive": true}"#);
+        assert_eq!(rated.status, 200);
+        assert_eq!(rated.json(), serde_json::json!({"data": {"id": 7, "rating": 1}}));
+
+        server.request("POST", "/api/review_ratings", &json, r#"{"id": 7, "positive": false}"#);
+        server.request("POST", "/api/review_ratings", &json, r#"{"id": 7, "positive": false}"#);
+        let ratings = server.get("/api/review_ratings", &[]);
+        assert_eq!(ratings.status, 200);
+        assert_eq!(ratings.json()["data"]["review_ratings"]["7"], -1);
+    });
+}
+
+#[test]
+fn rejects_bad_api_requests() {
+    for_each_mode(|server| {
+        let invalid = server.request("POST", "/api/review_ratings", &[], r#"{"id": 300}"#);
+        assert_eq!(invalid.status, 400);
+        assert_eq!(invalid.json()["error"]["code"], "invalid_body");
+
+        let wrong_method = server.request("DELETE", "/api/visits", &[], "");
+        assert_eq!(wrong_method.status, 405);
+        assert_eq!(wrong_method.header("Allow"), Some("GET"));
+    });
+}
+
+#[test]
+fn counts_visits() {
+    for_each_mode(|server| {
+        server.get("/", &[]);
+        let visits = server.get("/api/visits", &[]);
+        assert_eq!(visits.status, 200);
+        assert!(visits.json()["data"]["visits"].as_u64().unwrap() >= 1);
+    });
+}
+
+#[test]
+fn idle_connections_do_not_hold_up_other_clients() {
+    for_each_mode(|server| {
+        // Connected, but never sends a request. The server waits out the read timeout on it.
+        let mut idle = TcpStream::connect(server.addr).unwrap();
+        let mut partial = TcpStream::connect(server.addr).unwrap();
+        partial.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        let response = server.get("/", &[]);
+        assert_eq!(response.status, 200);
+        assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+
+        idle.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        idle.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        assert_eq!(Response::read(&mut idle, true).status, 200);
+    });
+}
+
+#[test]
+fn malformed_requests_are_bad_requests() {
+    for_each_mode(|server| {
+        let mut stream = TcpStream::connect(server.addr).unwrap();
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        stream.write_all(b"NOT HTTP\r\n\r\n").unwrap();
+        assert_eq!(Response::read(&mut stream, true).status, 400);
+
+        // Hanging up partway through is answered the same, whatever did arrive can't be parsed.
+        let mut stream = TcpStream::connect(server.addr).unwrap();
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        stream.write_all(b"GET / HT").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(Response::read(&mut stream, true).status, 400);
+    });
+}
+
+#[test]
+fn slow_requests_time_out() {
+    for mode in [ServerMode::Threaded, ServerMode::EventLoop] {
+        let server = TestServer::start_with(mode, |config| config.timeouts.read_timeout_ms = 200);
+        let mut stream = TcpStream::connect(server.addr).unwrap();
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+
+        let response = Response::read(&mut stream, true);
+        assert_eq!(response.status, 408);
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+}
+
+#[test]
+fn one_client_cannot_take_every_connection() {
+    for mode in [ServerMode::Threaded, ServerMode::EventLoop] {
+        let server = TestServer::start_with(mode, |config| {
+            config.server.queue_max_size = 4;
+            config.rate_limit.enabled = true;
+            config.rate_limit.max_connections_per_ip = 2;
+        });
+
+        // Connected but never sending anything, the way a slowloris holds its sockets open. More of them than the
+        // server has room for, only the first two are kept.
+        let mut idle: Vec<_> = (0..6).map(|_| TcpStream::connect(server.addr).unwrap()).collect();
+        for refused in &mut idle[2..] {
+            refused.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+            let response = Response::read(refused, true);
+            assert_eq!(response.status, 429);
+            assert_eq!(response.header("Retry-After"), Some("1"));
+        }
+
+        let started = Instant::now();
+        let mut other = connect_from(Ipv4Addr::new(127, 0, 0, 2), server.addr);
+        other.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        other.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(Response::read(&mut other, true).status, 200);
+        assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+        drop(idle);
+    }
+}
+
+#[test]
+fn connections_over_the_cap_are_turned_away() {
+    let server = TestServer::start_with(ServerMode::EventLoop, |config| config.server.queue_max_size = 2);
+    let idle: Vec<_> = (0..2).map(|_| TcpStream::connect(server.addr).unwrap()).collect();
+    for _ in 0..8 {
+        let mut refused = TcpStream::connect(server.addr).unwrap();
+        refused.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+        assert_eq!(Response::read(&mut refused, true).status, 503);
+        // Closed straight away rather than kept to finish writing.
+        assert_eq!(refused.read(&mut [0; 16]).unwrap_or(0), 0);
+    }
+
+    drop(idle);
+    let started = Instant::now();
+    loop {
+        let response = server.get("/", &[]);
+        if response.status == 200 {
+            break;
+        }
+        assert_eq!(response.status, 503);
+        assert!(started.elapsed() < CLIENT_TIMEOUT, "the idle connections were never let go");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn requests_over_the_size_limit_are_refused() {
+    for_each_mode(|server| {
+        let send = |request: &[u8]| {
+            let mut stream = TcpStream::connect(server.addr).unwrap();
+            stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+            stream.write_all(request).unwrap();
+            Response::read(&mut stream, true).status
+        };
+
+        let mut headers = b"GET / HTTP/1.1\r\nHost: localhost\r\n".to_vec();
+        while headers.len() < 9000 {
+            headers.extend_from_slice(b"X-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n");
+        }
+        assert_eq!(send(&headers), 431);
+
+        let mut body = b"POST /api/review_ratings HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9000\r\n\r\n".to_vec();
+        body.resize(body.len() + 9000, b' ');
+        assert_eq!(send(&body), 413);
+    });
+}